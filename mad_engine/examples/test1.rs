@@ -3,14 +3,14 @@
 
 use log::*;
 use mad_engine::FileEngine;
-use tokio::time::Duration;
+use std::time::Duration;
 
 const PATH: &str = "data";
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let (handle, mut opts) = FileEngine::new(
+    let (handle, _opts) = FileEngine::new(
         PATH,
         std::env::args().nth(1).expect("expect config file"),
         "0x3",
@@ -27,12 +27,8 @@ async fn main() {
     info!("get handle success");
     handle.create("file1".to_string()).unwrap();
     info!("create file success");
-    handle.remove("file1".to_string()).unwrap();
+    handle.remove("file1".to_string()).await.unwrap();
     info!("remove file success");
-    handle.unload_bs().await.unwrap();
-    info!("unload blobstore success");
-    drop(handle);
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    opts.finish();
+    handle.close(Duration::from_secs(10)).await.unwrap();
     info!("close engine success");
 }
@@ -3,7 +3,7 @@
 
 use log::*;
 use mad_engine::*;
-use tokio::time::Duration;
+use std::time::Duration;
 
 // read write data length
 const DATA_LEN: usize = 512;
@@ -19,7 +19,7 @@ const PATH: &str = "data";
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let (handle, mut opts) = FileEngine::new(
+    let (handle, _opts) = FileEngine::new(
         PATH,
         std::env::args().nth(1).expect("expect config file"),
         "0x3",
@@ -58,12 +58,8 @@ async fn main() {
         }
     }
     info!("data match!");
-    handle.remove("file2".into()).unwrap();
+    handle.remove("file2".into()).await.unwrap();
     info!("remove file2 success");
-    handle.unload_bs().await.unwrap();
-    info!("unload blobstore success");
-    drop(handle);
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    opts.finish();
+    handle.close(Duration::from_secs(10)).await.unwrap();
     info!("close engine success");
 }
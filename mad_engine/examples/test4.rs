@@ -2,10 +2,10 @@
 
 use log::*;
 use mad_engine::*;
+use std::time::Duration;
 
 const DATA_LEN: usize = 6144;
 const PATH: &str = "data";
-use tokio::time::Duration;
 
 /*
     |0-----------3800-----4000----4099----4199----------6143| <6144 in total>
@@ -18,7 +18,7 @@ use tokio::time::Duration;
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let (handle, mut opts) = FileEngine::new(
+    let (handle, _opts) = FileEngine::new(
         PATH,
         std::env::args().nth(1).expect("expect config file"),
         "0x3",
@@ -111,11 +111,7 @@ async fn main() {
     }
     info!("third read success");
 
-    handle.remove("file4".to_owned()).unwrap();
-    handle.unload_bs().await.unwrap();
-    info!("unload blobstore success");
-    drop(handle);
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    opts.finish();
+    handle.remove("file4".to_owned()).await.unwrap();
+    handle.close(Duration::from_secs(10)).await.unwrap();
     info!("close engine success");
 }
@@ -3,7 +3,7 @@
 
 use log::*;
 use mad_engine::*;
-use tokio::time::Duration;
+use std::time::Duration;
 
 const DATA_LEN2: usize = 512;
 const DATA_LEN3: usize = 5120;
@@ -14,7 +14,7 @@ const PATH: &str = "data";
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let (handle, mut opts) = FileEngine::new(
+    let (handle, _opts) = FileEngine::new(
         PATH,
         std::env::args().nth(1).expect("expect config file"),
         // "config_file.json".to_string(),
@@ -32,7 +32,7 @@ async fn main() {
     info!("get handle success");
 
     handle.create("file1".to_string()).unwrap();
-    handle.remove("file1".to_string()).unwrap();
+    handle.remove("file1".to_string()).await.unwrap();
     info!("====== test1 pass...");
 
     handle.create("file2".to_string()).unwrap();
@@ -54,7 +54,7 @@ async fn main() {
             error!("data mismatch on {}!", i);
         }
     }
-    handle.remove("file2".into()).unwrap();
+    handle.remove("file2".into()).await.unwrap();
     info!("====== test2 pass...");
 
     handle.create("file3".into()).unwrap();
@@ -77,7 +77,7 @@ async fn main() {
             error!("data mismatch on {}!", i);
         }
     }
-    handle.remove("file3".into()).unwrap();
+    handle.remove("file3".into()).await.unwrap();
     info!("====== test3 pass...");
 
     handle.create("file4".to_string()).unwrap();
@@ -145,13 +145,10 @@ async fn main() {
         }
     }
 
-    handle.remove("file4".to_owned()).unwrap();
+    handle.remove("file4".to_owned()).await.unwrap();
     info!("====== test4 pass...");
 
-    handle.unload_bs().await.unwrap();
-    drop(handle);
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    opts.finish();
+    handle.close(Duration::from_secs(10)).await.unwrap();
 
     info!("====== env close");
 }
@@ -2,7 +2,7 @@
 
 use log::*;
 use mad_engine::*;
-use tokio::time::Duration;
+use std::time::Duration;
 
 const DATA_LEN: usize = 6144;
 const PATH: &str = "data";
@@ -10,7 +10,7 @@ const PATH: &str = "data";
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let (handle, mut opts) = FileEngine::new(
+    let (handle, _opts) = FileEngine::new(
         PATH,
         std::env::args().nth(1).expect("expect config file"),
         "0x3",
@@ -49,10 +49,6 @@ async fn main() {
         }
     }
     info!("first data match");
-    handle.unload_bs().await.unwrap();
-    info!("unload blobstore success");
-    drop(handle);
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    opts.finish();
+    handle.close(Duration::from_secs(10)).await.unwrap();
     info!("close engine success");
 }
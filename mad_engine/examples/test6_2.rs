@@ -19,7 +19,7 @@ async fn main() {
     info!("=============restore=============");
     tokio::time::sleep(Duration::from_secs(2)).await;
 
-    let (handle, mut opts) = FileEngine::new(
+    let (handle, _opts) = FileEngine::new(
         PATH,
         std::env::args().nth(1).expect("expect config file"),
         "0x3",
@@ -61,12 +61,8 @@ async fn main() {
         }
     }
     info!("second data match");
-    // handle.remove("file6".to_owned()).unwrap();
+    // handle.remove("file6".to_owned()).await.unwrap();
 
-    handle.unload_bs().await.unwrap();
-    info!("unload blobstore success");
-    drop(handle);
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    opts.finish();
+    handle.close(Duration::from_secs(10)).await.unwrap();
     info!("test6 pass...");
 }
@@ -1,5 +1,6 @@
 use log::*;
 use mad_engine::{BsBindOpts, EngineOpts};
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
@@ -16,13 +17,13 @@ async fn main() {
     opts.set_config_file(config);
     opts.set_name("Test Basic");
     opts.start_spdk(false);
-    opts.ready();
+    opts.ready(Duration::from_secs(60)).await.unwrap();
     info!("got ready");
 
     let be = opts.create_be();
     info!("create_be success");
 
-    be.unload().await;
+    be.unload().await.unwrap();
     info!("close success");
     opts.finish();
     info!("App wait fot close");
@@ -1,13 +1,13 @@
 use log::*;
 use mad_engine::*;
+use std::time::Duration;
 
 const PATH: &str = "data";
-use tokio::time::Duration;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let (handle, mut opts) = FileEngine::new(
+    let (handle, _opts) = FileEngine::new(
         PATH,
         std::env::args().nth(1).expect("expect config file"),
         "0x3",
@@ -40,12 +40,8 @@ async fn main() {
     let size3 = handle.stat("file1".to_string()).unwrap().get_size();
     info!("size3 = {}", size3);
 
-    handle.remove("file1".to_string()).unwrap();
+    handle.remove("file1".to_string()).await.unwrap();
     info!("remove file success");
-    handle.unload_bs().await.unwrap();
-    info!("unload blobstore success");
-    drop(handle);
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    opts.finish();
+    handle.close(Duration::from_secs(10)).await.unwrap();
     info!("close engine success");
 }
@@ -21,7 +21,7 @@ async fn main() {
     opts.set_config_file(config);
     opts.set_name("Test Basic");
     opts.start_spdk(false);
-    opts.ready();
+    opts.ready(Duration::from_secs(60)).await.unwrap();
     info!("got ready");
 
     let be = opts.create_be();
@@ -45,12 +45,12 @@ async fn main() {
     write_buf.as_mut().fill(0x5a);
     info!("Write buff success");
 
-    be.write(0, bid, write_buf.as_ref()).await.unwrap();
+    let write_buf = be.write(0, bid, write_buf).await.unwrap();
     info!("Write Success");
 
-    let mut read_buf = env::DmaBuf::alloc(io_unit_size as usize, 0x1000);
+    let read_buf = env::DmaBuf::alloc(io_unit_size as usize, 0x1000);
 
-    be.read(0, bid, read_buf.as_mut()).await.unwrap();
+    let read_buf = be.read(0, bid, read_buf).await.unwrap();
     info!("Read Success");
 
     if write_buf.as_ref() != read_buf.as_ref() {
@@ -65,7 +65,7 @@ async fn main() {
     be.delete_blob(bid).await.unwrap();
     info!("Blob Delete Success");
 
-    be.unload().await;
+    be.unload().await.unwrap();
     info!("unload success");
     opts.finish();
     info!("App wait fot close");
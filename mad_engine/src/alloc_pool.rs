@@ -0,0 +1,164 @@
+//! This module runs page allocation on worker threads of one engine
+//!
+//! Each worker owns the `ThreadData` of its blobs, a job is run by any
+//! idle worker on that worker's state
+
+use crate::common::ThreadData;
+use log::*;
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+};
+
+type Job = Box<dyn FnOnce(&mut ThreadData) + Send>;
+
+pub struct AllocPool {
+    // allocator state of each worker
+    states: Vec<Arc<Mutex<ThreadData>>>,
+    // none once the pool is dropped
+    jobs: Mutex<Option<mpsc::Sender<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl AllocPool {
+    /// start `num_workers` workers with empty state, worker i is pinned to
+    /// `cores[i % cores.len()]` if `cores` is not empty
+    pub fn new(num_workers: usize, cores: &[usize]) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let states: Vec<Arc<Mutex<ThreadData>>> = (0..num_workers.max(1))
+            .map(|_| Arc::new(Mutex::new(ThreadData::default())))
+            .collect();
+        let workers = states
+            .iter()
+            .enumerate()
+            .map(|(i, state)| {
+                let state = state.clone();
+                let rx = rx.clone();
+                let core = (!cores.is_empty()).then(|| cores[i % cores.len()]);
+                std::thread::spawn(move || {
+                    if let Some(core) = core {
+                        pin_to_core(core);
+                    }
+                    loop {
+                        let job = rx.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(&mut state.lock().unwrap()),
+                            // the pool is dropped
+                            Err(_) => break,
+                        }
+                    }
+                })
+            })
+            .collect();
+        Self {
+            states,
+            jobs: Mutex::new(Some(tx)),
+            workers,
+        }
+    }
+
+    /// allocator state of each worker, indexed by worker
+    pub fn states(&self) -> &[Arc<Mutex<ThreadData>>] {
+        &self.states
+    }
+
+    /// run `f` on the state of an idle worker and wait for its result
+    pub fn run<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ThreadData) -> T + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let job: Job = Box::new(move |state| {
+            let _ = tx.send(f(state));
+        });
+        self.jobs
+            .lock()
+            .unwrap()
+            .as_ref()
+            .expect("allocator pool is dropped")
+            .send(job)
+            .expect("allocator workers exit");
+        rx.recv().expect("allocator job panics")
+    }
+}
+
+/// pin the current thread to a core, a failure only loses locality
+fn pin_to_core(core: usize) {
+    if core >= libc::CPU_SETSIZE as usize {
+        warn!(
+            "fail to pin allocator worker to core {}: no such core",
+            core
+        );
+        return;
+    }
+    let ret = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if ret != 0 {
+        warn!(
+            "fail to pin allocator worker to core {}: {}",
+            core,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+impl Drop for AllocPool {
+    fn drop(&mut self) {
+        // workers exit once the job queue is closed
+        self.jobs.lock().unwrap().take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::BlobRef;
+    use async_spdk::blob::BlobId;
+
+    #[test]
+    pub fn test_run() {
+        let pool = AllocPool::new(2, &[]);
+        assert_eq!(pool.states().len(), 2);
+        let blob = BlobRef {
+            bs: 0,
+            bid: BlobId::default(),
+        };
+        let rets: Vec<usize> = (0..8)
+            .map(|i| {
+                pool.run(move |state| {
+                    state.tblobs.push(blob);
+                    i
+                })
+            })
+            .collect();
+        assert_eq!(rets, (0..8).collect::<Vec<_>>());
+        // every job ran on the state of one worker
+        let blobs: usize = pool
+            .states()
+            .iter()
+            .map(|state| state.lock().unwrap().tblobs.len())
+            .sum();
+        assert_eq!(blobs, 8);
+    }
+
+    #[test]
+    pub fn test_workers() {
+        // at least one worker runs jobs
+        let pool = AllocPool::new(0, &[]);
+        assert_eq!(pool.states().len(), 1);
+        assert_eq!(pool.run(|_| 1), 1);
+        // a core that can not be pinned to only loses locality
+        let pool = AllocPool::new(2, &[usize::MAX]);
+        assert_eq!(pool.states().len(), 2);
+        assert_eq!(pool.run(|_| 2), 2);
+    }
+}
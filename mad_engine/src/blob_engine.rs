@@ -2,16 +2,18 @@
 //!
 //! Basically like a message passing module
 
+use futures::future::join_all;
 use log::*;
-use std::ffi::c_void;
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
 
-use async_spdk::blob::{Blob, BlobId, Blobstore, IoChannel};
-use async_spdk::event::SpdkEvent;
-use tokio::sync::Notify;
+use async_spdk::blob::{Blob, BlobId, Blobstore};
+use async_spdk::env::DmaBuf;
 
-use crate::error::Result;
-use crate::{EngineBlob, Msg, Op};
+use crate::error::{EngineError, Result};
+use crate::{BlobIo, CoreBound, CoreChannel, Done, Msg, Op, Request};
 
 pub struct BlobEngine {
     // Intuitively each blobstore need its own BlobEngine
@@ -20,10 +22,8 @@ pub struct BlobEngine {
     // Which core to play I/O, note that each core binds one BlobStore
     pub core: u32,
     pub io_size: u64,
-    // io_channel to perform I/O
-    pub channel: Option<IoChannel>,
-    // Blobstore
-    pub bs: Arc<Mutex<Blobstore>>,
+    // Blobstore, only used on its own core
+    pub bs: Arc<CoreBound<Blobstore>>,
 }
 
 impl std::fmt::Display for BlobEngine {
@@ -60,155 +60,176 @@ impl BlobEngine {
 
 impl BlobEngine {
     /// New a blob engine
-    pub(crate) fn new(name: &str, core: u32, io_size: u64, bs: Arc<Mutex<Blobstore>>) -> Self {
+    pub(crate) fn new(name: &str, core: u32, io_size: u64, bs: Arc<CoreBound<Blobstore>>) -> Self {
         BlobEngine {
             name: name.to_string(),
             core,
             io_size,
-            channel: None,
             bs,
         }
     }
 
+    /// Send a message to the owning core and wait for its completion
+    ///
+    /// A failed SPDK call comes back as `EngineError::SPDKError`
+    async fn call(&self, m: Msg) -> Result<Done> {
+        CoreChannel::new(self.core).call(m).await?
+    }
+
     /// Unload BlobStore
     ///
     /// All blobs must be closed
-    pub(crate) async fn unload(&self) {
-        let n = Arc::new(Notify::new());
-        let m = Msg::gen_unload(n.clone(), self.bs.clone());
-        let e = SpdkEvent::alloc(
-            self.core,
-            Self::op_helper as *const () as *mut c_void,
-            Box::into_raw(Box::new((m, n.clone()))) as *mut c_void,
-        )
-        .unwrap();
-        e.call().unwrap();
-        // info!("Wait for unload notify");
-        n.notified().await;
+    pub(crate) async fn unload(&self) -> Result<()> {
+        let m = Msg::gen_unload(self.bs.clone());
+        Self::call(self, m).await.map_err(|e| {
+            error!("fail to unload blobstore {}: {}", self.name, e);
+            e
+        })?;
+        Ok(())
     }
 
-    /// Write data to given blob
-    pub(crate) async fn write(&self, offset: u64, bid: BlobId, buf: &[u8]) -> Result<()> {
+    /// Write data to given blob, the buffer is handed back
+    pub(crate) async fn write(&self, offset: u64, bid: BlobId, buf: DmaBuf) -> Result<DmaBuf> {
         let blob = Self::open_blob(self, bid).await?;
-        let n = Arc::new(Notify::new());
-        let m = Msg::gen_write(n.clone(), self.bs.clone(), offset, blob, buf);
-        let e = SpdkEvent::alloc(
-            self.core,
-            Self::op_helper as *const () as *mut c_void,
-            Box::into_raw(Box::new((m, n.clone()))) as *mut c_void,
-        )
-        .unwrap();
-        e.call().unwrap();
-        // info!("Wait for write notify");
-        n.notified().await;
+        let m = Msg::gen_write(self.bs.clone(), offset, blob, buf);
+        let ret = Self::call(self, m).await;
         Self::close_blob(self, blob).await?;
-        Ok(())
+        match ret {
+            Ok(Done::Buf(buf)) => Ok(buf),
+            Ok(_) => unreachable!(),
+            Err(e) => {
+                error!("fail to write blob {}, offset {}: {}", bid, offset, e);
+                Err(e)
+            }
+        }
     }
 
-    /// Read data from a given blob
+    /// Read data from a given blob into the buffer, which is handed back
     ///
     /// TODO: this should return read size
-    pub(crate) async fn read(&self, offset: u64, bid: BlobId, buf: &mut [u8]) -> Result<()> {
+    pub(crate) async fn read(&self, offset: u64, bid: BlobId, buf: DmaBuf) -> Result<DmaBuf> {
         let blob = Self::open_blob(self, bid).await?;
-        let n = Arc::new(Notify::new());
-        let m = Msg::gen_read(n.clone(), self.bs.clone(), offset, blob, buf);
-        let e = SpdkEvent::alloc(
-            self.core,
-            Self::op_helper as *const () as *mut c_void,
-            Box::into_raw(Box::new((m, n.clone()))) as *mut c_void,
-        )
-        .unwrap();
-        e.call().unwrap();
-        // info!("Wait for read notify");
-        n.notified().await;
+        let m = Msg::gen_read(self.bs.clone(), offset, blob, buf);
+        let ret = Self::call(self, m).await;
         Self::close_blob(self, blob).await?;
-        Ok(())
+        match ret {
+            Ok(Done::Buf(buf)) => Ok(buf),
+            Ok(_) => unreachable!(),
+            Err(e) => {
+                error!("fail to read blob {}, offset {}: {}", bid, offset, e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Read and write several pages with a single event to the owning core
+    ///
+    /// Each blob touched is opened once, each I/O is handed back with its
+    /// status in submission order
+    pub(crate) async fn batch(&self, ios: Vec<BlobIo>) -> Result<Vec<(BlobIo, Result<()>)>> {
+        if ios.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut blobs = HashMap::new();
+        let mut opened = Ok(());
+        for io in ios.iter() {
+            let bid = io.blob_id();
+            if !blobs.contains_key(&bid) {
+                match Self::open_blob(self, bid).await {
+                    Ok(blob) => {
+                        blobs.insert(bid, blob);
+                    }
+                    Err(e) => {
+                        opened = Err(e);
+                        break;
+                    }
+                }
+            }
+        }
+        let ret = match opened {
+            Ok(()) => {
+                let batch = ios
+                    .into_iter()
+                    .map(|io| (blobs[&io.blob_id()], io))
+                    .collect();
+                let m = Msg::gen_batch(self.bs.clone(), batch);
+                Self::call(self, m).await
+            }
+            Err(e) => Err(e),
+        };
+        // every blob opened is closed whatever fails, the first close error
+        // is returned after the results
+        let mut closed = Ok(());
+        for (_, blob) in blobs {
+            closed = closed.and(Self::close_blob(self, blob).await);
+        }
+        let rets = match ret? {
+            Done::Batch(rets) => rets
+                .into_iter()
+                .map(|(io, ret)| (io, ret.map_err(EngineError::from)))
+                .collect(),
+            _ => unreachable!(),
+        };
+        closed?;
+        Ok(rets)
     }
 
     /// Delete a blob
     #[allow(unused)]
     pub(crate) async fn delete_blob(&self, blob_id: BlobId) -> Result<()> {
-        let n = Arc::new(Notify::new());
-        let m = Msg::gen_delete(n.clone(), self.bs.clone(), blob_id);
-        let e = SpdkEvent::alloc(
-            self.core,
-            Self::op_helper as *const () as *mut c_void,
-            Box::into_raw(Box::new((m, n.clone()))) as *mut c_void,
-        )
-        .unwrap();
-        e.call().unwrap();
-        // info!("Wait for delete notify");
-        n.notified().await;
+        let m = Msg::gen_delete(self.bs.clone(), blob_id);
+        Self::call(self, m).await.map_err(|e| {
+            error!("fail to delete blob {}: {}", blob_id, e);
+            e
+        })?;
         Ok(())
     }
 
     /// Create empty blob
     pub(crate) async fn create_blob(&self) -> Result<BlobId> {
-        let n = Arc::new(Notify::new());
-        let m = Msg::gen_create(n.clone(), self.bs.clone());
-        let bid = Arc::new(Mutex::new(BlobId::default()));
-        let e = SpdkEvent::alloc(
-            self.core,
-            Self::create_helper as *const () as *mut c_void,
-            Box::into_raw(Box::new((m, bid.clone(), n.clone()))) as *mut c_void,
-        )
-        .unwrap();
-        e.call().unwrap();
-        // info!("Wait for create notify");
-        n.notified().await;
-        let b = { *bid.lock().unwrap() };
-        Ok(b)
+        let m = Msg::gen_create(self.bs.clone());
+        match Self::call(self, m).await {
+            Ok(Done::BlobId(bid)) => Ok(bid),
+            Ok(_) => unreachable!(),
+            Err(e) => {
+                error!("fail to create blob on {}: {}", self.name, e);
+                Err(e)
+            }
+        }
     }
 
     /// Open a blob, get blob handle
     pub(crate) async fn open_blob(&self, bid: BlobId) -> Result<Blob> {
-        let n = Arc::new(Notify::new());
-        let m = Msg::gen_open(n.clone(), self.bs.clone(), bid);
-        let blob = Arc::new(Mutex::new(Blob::default()));
-        let e = SpdkEvent::alloc(
-            self.core,
-            Self::open_helper as *const () as *mut c_void,
-            Box::into_raw(Box::new((m, blob.clone(), n.clone()))) as *mut c_void,
-        )
-        .unwrap();
-        e.call().unwrap();
-        // info!("Wait for open notify");
-        n.notified().await;
-        let b = { *blob.lock().unwrap() };
-        Ok(b)
+        let m = Msg::gen_open(self.bs.clone(), bid);
+        match Self::call(self, m).await {
+            Ok(Done::Blob(blob)) => Ok(blob),
+            Ok(_) => unreachable!(),
+            Err(e) => {
+                error!("fail to open blob {}: {}", bid, e);
+                Err(e)
+            }
+        }
     }
 
     /// Resize a blob
     ///
     /// Blob creation only creates null blob
     pub(crate) async fn resize_blob(&self, blob: Blob, size: u64) -> Result<()> {
-        let n = Arc::new(Notify::new());
-        let m = Msg::gen_resize(n.clone(), self.bs.clone(), blob, size);
-        let e = SpdkEvent::alloc(
-            self.core,
-            Self::op_helper as *const () as *mut c_void,
-            Box::into_raw(Box::new((m, n.clone()))) as *mut c_void,
-        )
-        .unwrap();
-        e.call().unwrap();
-        // info!("Wait for Resize notify");
-        n.notified().await;
+        let m = Msg::gen_resize(self.bs.clone(), blob, size);
+        Self::call(self, m).await.map_err(|e| {
+            error!("fail to resize blob to {} clusters: {}", size, e);
+            e
+        })?;
         Ok(())
     }
 
     /// Blob metadata sync
     pub(crate) async fn sync_blob(&self, blob: Blob) -> Result<()> {
-        let n = Arc::new(Notify::new());
-        let m = Msg::gen_sync(n.clone(), self.bs.clone(), blob);
-        let e = SpdkEvent::alloc(
-            self.core,
-            Self::op_helper as *const () as *mut c_void,
-            Box::into_raw(Box::new((m, n.clone()))) as *mut c_void,
-        )
-        .unwrap();
-        e.call().unwrap();
-        // info!("Wait for sync notify");
-        n.notified().await;
+        let m = Msg::gen_sync(self.bs.clone(), blob);
+        Self::call(self, m).await.map_err(|e| {
+            error!("fail to sync blob metadata: {}", e);
+            e
+        })?;
         Ok(())
     }
 
@@ -216,142 +237,108 @@ impl BlobEngine {
     ///
     /// All blobs must be closed before unload blobstore
     pub(crate) async fn close_blob(&self, blob: Blob) -> Result<()> {
-        let n = Arc::new(Notify::new());
-        let m = Msg::gen_close(n.clone(), self.bs.clone(), blob);
-        let e = SpdkEvent::alloc(
-            self.core,
-            Self::op_helper as *const () as *mut c_void,
-            Box::into_raw(Box::new((m, n.clone()))) as *mut c_void,
-        )
-        .unwrap();
-        e.call().unwrap();
-        // info!("Wait for close notify");
-        n.notified().await;
+        let m = Msg::gen_close(self.bs.clone(), blob);
+        Self::call(self, m).await.map_err(|e| {
+            error!("fail to close blob: {}", e);
+            e
+        })?;
         Ok(())
     }
 
-    fn create_helper(arg: *mut c_void) {
-        let (m, bid, n) =
-            unsafe { *Box::from_raw(arg as *mut (Msg, Arc<Mutex<BlobId>>, Arc<Notify>)) };
-        m.bs.as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .create_blob_sync(Box::into_raw(Box::new((bid, n))) as *mut c_void)
-            .unwrap();
-        info!("Create Blob");
-    }
-
-    fn open_helper(arg: *mut c_void) {
-        let (m, blob, n) =
-            unsafe { *Box::from_raw(arg as *mut (Msg, Arc<Mutex<EngineBlob>>, Arc<Notify>)) };
-        let bid = m.blob_id.as_ref().unwrap();
-        m.bs.as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .open_blob_sync(bid, Box::into_raw(Box::new((blob, n))) as *mut c_void)
-            .unwrap();
-    }
-
-    fn op_helper(arg: *mut c_void) {
-        let (mut m, n) = unsafe { *Box::from_raw(arg as *mut (Msg, Arc<Notify>)) };
+    /// Run a message on the reactor
+    async fn run_op(m: Msg) -> Result<Done> {
+        // the message owns its blobstore handle, which lives across awaits
+        let bs_ref = m.bs.clone().unwrap();
+        let bs = match bs_ref.get() {
+            Some(bs) => bs,
+            None => return Err(EngineError::WrongCore(bs_ref.core())),
+        };
         match m.op {
-            Op::IoSize => unimplemented!(),
-            Op::Channel => unimplemented!(),
+            Op::IoSize => Err(EngineError::Unsupported("io size")),
+            Op::Channel => Err(EngineError::Unsupported("io channel")),
             Op::Write => {
-                let channel = {
-                    m.bs.as_ref()
-                        .unwrap()
-                        .lock()
-                        .unwrap()
-                        .alloc_io_channel()
-                        .unwrap()
-                };
+                let channel = bs.alloc_io_channel()?;
+                let buf = m.buf.unwrap();
                 m.blob
-                    .as_ref()
                     .unwrap()
-                    .write_sync(
-                        &channel,
-                        m.offset.unwrap(),
-                        m.write_buf.unwrap(),
-                        Box::into_raw(Box::new(n)) as *mut c_void,
-                    )
-                    .unwrap();
+                    .write(&channel, m.offset.unwrap(), buf.as_ref())
+                    .await?;
                 info!("Write Blob");
+                Ok(Done::Buf(buf))
             }
             Op::Read => {
-                let channel = {
-                    m.bs.as_ref()
-                        .unwrap()
-                        .lock()
-                        .unwrap()
-                        .alloc_io_channel()
-                        .unwrap()
-                };
+                let channel = bs.alloc_io_channel()?;
+                let mut buf = m.buf.unwrap();
                 m.blob
-                    .as_ref()
                     .unwrap()
-                    .read_sync(
-                        &channel,
-                        m.offset.unwrap(),
-                        m.read_buf.as_mut().unwrap(),
-                        Box::into_raw(Box::new(n)) as *mut c_void,
-                    )
-                    .unwrap();
+                    .read(&channel, m.offset.unwrap(), buf.as_mut())
+                    .await?;
                 info!("Read Blob");
+                Ok(Done::Buf(buf))
+            }
+            Op::Batch => {
+                let channel = bs.alloc_io_channel()?;
+                let batch = m.batch.unwrap();
+                let count = batch.len();
+                let channel = &channel;
+                let rets = join_all(batch.into_iter().map(|(blob, mut io)| async move {
+                    let ret = match &mut io {
+                        BlobIo::Read(_, offset, buf) => {
+                            blob.read(channel, *offset, buf.as_mut()).await
+                        }
+                        BlobIo::Write(_, offset, buf) => {
+                            blob.write(channel, *offset, buf.as_ref()).await
+                        }
+                    };
+                    (io, ret)
+                }))
+                .await;
+                trace!("Batch {} I/Os", count);
+                Ok(Done::Batch(rets))
             }
             Op::Create => {
-                todo!();
+                let bid = bs.create_blob().await?;
+                info!("Create Blob");
+                Ok(Done::BlobId(bid))
+            }
+            Op::Open => {
+                let blob = bs.open_blob(m.blob_id.unwrap()).await?;
+                Ok(Done::Blob(blob))
             }
             Op::Delete => {
-                let bid = m.blob_id.as_ref().unwrap();
-                m.bs.as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .delete_blob_sync(bid, Box::into_raw(Box::new(n)) as *mut c_void)
-                    .unwrap();
+                bs.delete_blob(m.blob_id.unwrap()).await?;
                 info!("Delete Blob");
+                Ok(Done::Unit)
             }
-            Op::ClusterCount => unimplemented!(),
+            Op::ClusterCount => Err(EngineError::Unsupported("cluster count")),
             Op::Unload => {
-                m.bs.as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .unload_sync(Box::into_raw(Box::new(n)) as *mut c_void)
-                    .unwrap();
+                bs.unload().await?;
                 info!("Unload BlobStore");
+                Ok(Done::Unit)
             }
             Op::Resize => {
-                m.blob
-                    .as_ref()
-                    .unwrap()
-                    .resize_sync(
-                        m.blob_size.unwrap(),
-                        Box::into_raw(Box::new(n)) as *mut c_void,
-                    )
-                    .unwrap();
+                m.blob.unwrap().resize(m.blob_size.unwrap()).await?;
                 info!("Resize Blob");
+                Ok(Done::Unit)
             }
             Op::Sync => {
-                m.blob
-                    .as_ref()
-                    .unwrap()
-                    .sync_metadata_sync(Box::into_raw(Box::new(n)) as *mut c_void)
-                    .unwrap();
+                m.blob.unwrap().sync_metadata().await?;
                 info!("Sync Metadata");
+                Ok(Done::Unit)
             }
             Op::Close => {
-                m.blob
-                    .as_ref()
-                    .unwrap()
-                    .close_sync(Box::into_raw(Box::new(n)) as *mut c_void)
-                    .unwrap();
+                m.blob.unwrap().close().await?;
                 info!("Close Blob");
+                Ok(Done::Unit)
             }
-            _ => unimplemented!(),
         }
     }
 }
+
+impl Request for Msg {
+    type Response = Result<Done>;
+
+    fn serve(self) -> Pin<Box<dyn Future<Output = Self::Response>>> {
+        Box::pin(BlobEngine::run_op(self))
+    }
+}
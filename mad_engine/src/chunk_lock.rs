@@ -0,0 +1,384 @@
+//! This module serializes conflicting operations on a chunk
+//!
+//! Byte ranges are locked shared by reads and exclusively by writes,
+//! metadata updates of a chunk are serialized by a striped mutex, every
+//! range of every chunk can be locked at once by one holder
+
+use crate::error::{EngineError, Result};
+use std::{
+    collections::hash_map::DefaultHasher,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard},
+};
+use tokio::sync::oneshot;
+
+/// number of metadata mutexes
+const META_STRIPES: usize = 64;
+
+struct HeldRange {
+    id: u64,
+    start: u64,
+    end: u64,
+    exclusive: bool,
+}
+
+#[derive(Default)]
+struct LockTable {
+    // chunk name -> held ranges
+    held: HashMap<String, Vec<HeldRange>>,
+    // tasks waiting for any range to be released
+    waiters: Vec<oneshot::Sender<()>>,
+    next_id: u64,
+    // every range is locked by an `AllGuard`
+    all: bool,
+    // no range can be locked any more
+    closed: bool,
+}
+
+pub struct ChunkLocks {
+    table: Mutex<LockTable>,
+    metas: Vec<Mutex<()>>,
+}
+
+/// a held range, released on drop
+pub struct RangeGuard<'a> {
+    locks: &'a ChunkLocks,
+    name: String,
+    id: u64,
+}
+
+/// every range of every chunk, released on drop
+pub struct AllGuard<'a> {
+    locks: &'a ChunkLocks,
+}
+
+impl Default for ChunkLocks {
+    fn default() -> Self {
+        Self {
+            table: Mutex::new(LockTable::default()),
+            metas: (0..META_STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+}
+
+impl ChunkLocks {
+    /// lock bytes [start, end) of a chunk, exclusive ranges conflict with
+    /// every overlapping range
+    pub async fn lock(
+        &self,
+        name: &str,
+        start: u64,
+        end: u64,
+        exclusive: bool,
+    ) -> Result<RangeGuard<'_>> {
+        let mut guards = self.lock_many(&[(name, start, end, exclusive)]).await?;
+        Ok(guards.pop().unwrap())
+    }
+
+    /// lock several ranges at once, `(name, start, end, exclusive)` each
+    ///
+    /// nothing is held until every range is free, so a caller never waits
+    /// on `lock_all` with part of its ranges taken
+    pub async fn lock_many(
+        &self,
+        ranges: &[(&str, u64, u64, bool)],
+    ) -> Result<Vec<RangeGuard<'_>>> {
+        loop {
+            let rx = {
+                let mut t = self.table.lock().unwrap();
+                if t.closed {
+                    return Err(EngineError::Closed);
+                }
+                let conflict = t.all
+                    || ranges.iter().any(|&(name, start, end, exclusive)| {
+                        t.held.get(name).is_some_and(|held| {
+                            held.iter().any(|r| {
+                                r.start < end && start < r.end && (r.exclusive || exclusive)
+                            })
+                        })
+                    });
+                if !conflict {
+                    let mut guards = Vec::with_capacity(ranges.len());
+                    for &(name, start, end, exclusive) in ranges {
+                        let id = t.next_id;
+                        t.next_id += 1;
+                        t.held.entry(name.to_string()).or_default().push(HeldRange {
+                            id,
+                            start,
+                            end,
+                            exclusive,
+                        });
+                        guards.push(RangeGuard {
+                            locks: self,
+                            name: name.to_string(),
+                            id,
+                        });
+                    }
+                    return Ok(guards);
+                }
+                let (tx, rx) = oneshot::channel();
+                t.waiters.push(tx);
+                rx
+            };
+            let _ = rx.await;
+        }
+    }
+
+    /// lock every range of every chunk
+    ///
+    /// ranges requested after the call wait, it returns once the ranges
+    /// already held are released
+    pub async fn lock_all(&self) -> Result<AllGuard<'_>> {
+        // take the table first, a dropped call gives it back with the guard
+        let guard = loop {
+            let rx = {
+                let mut t = self.table.lock().unwrap();
+                if t.closed {
+                    return Err(EngineError::Closed);
+                }
+                if !t.all {
+                    t.all = true;
+                    break AllGuard { locks: self };
+                }
+                let (tx, rx) = oneshot::channel();
+                t.waiters.push(tx);
+                rx
+            };
+            let _ = rx.await;
+        };
+        loop {
+            let rx = {
+                let mut t = self.table.lock().unwrap();
+                if t.held.is_empty() {
+                    return Ok(guard);
+                }
+                let (tx, rx) = oneshot::channel();
+                t.waiters.push(tx);
+                rx
+            };
+            let _ = rx.await;
+        }
+    }
+
+    /// refuse new ranges and wait until every held range is released
+    pub async fn close(&self) {
+        loop {
+            let rx = {
+                let mut t = self.table.lock().unwrap();
+                t.closed = true;
+                if t.held.is_empty() && !t.all {
+                    return;
+                }
+                let (tx, rx) = oneshot::channel();
+                t.waiters.push(tx);
+                rx
+            };
+            let _ = rx.await;
+        }
+    }
+
+    /// lock metadata updates of a chunk
+    pub fn meta(&self, name: &str) -> MutexGuard<'_, ()> {
+        self.metas[Self::stripe(name)].lock().unwrap()
+    }
+
+    /// lock metadata updates of several chunks, in a fixed order
+    pub fn metas(&self, names: &[&str]) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = names.iter().map(|name| Self::stripe(name)).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|i| self.metas[i].lock().unwrap())
+            .collect()
+    }
+
+    fn stripe(name: &str) -> usize {
+        let mut h = DefaultHasher::new();
+        name.hash(&mut h);
+        h.finish() as usize % META_STRIPES
+    }
+
+    fn unlock(&self, name: &str, id: u64) {
+        let mut t = self.table.lock().unwrap();
+        if let Some(ranges) = t.held.get_mut(name) {
+            ranges.retain(|r| r.id != id);
+            if ranges.is_empty() {
+                t.held.remove(name);
+            }
+        }
+        Self::wake(&mut t);
+    }
+
+    fn unlock_all(&self) {
+        let mut t = self.table.lock().unwrap();
+        t.all = false;
+        Self::wake(&mut t);
+    }
+
+    fn wake(t: &mut LockTable) {
+        for waiter in t.waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+    }
+}
+
+impl Drop for RangeGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.unlock(&self.name, self.id);
+    }
+}
+
+impl Drop for AllGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.unlock_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    pub fn test_shared() {
+        let locks = ChunkLocks::default();
+        runtime().block_on(async {
+            let _a = locks.lock("a", 0, 8, false).await.unwrap();
+            let b = timeout(Duration::from_millis(100), locks.lock("a", 4, 12, false)).await;
+            assert!(b.is_ok());
+            // other chunks and disjoint ranges never conflict
+            let c = timeout(Duration::from_millis(100), locks.lock("a", 12, 16, true)).await;
+            assert!(c.is_ok());
+            let d = timeout(Duration::from_millis(100), locks.lock("b", 0, 8, true)).await;
+            assert!(d.is_ok());
+        });
+    }
+
+    #[test]
+    pub fn test_exclusive() {
+        let locks = ChunkLocks::default();
+        let order = Mutex::new(vec![]);
+        runtime().block_on(async {
+            let held = locks.lock("a", 0, 8, true).await.unwrap();
+            assert!(
+                timeout(Duration::from_millis(20), locks.lock("a", 4, 12, false))
+                    .await
+                    .is_err()
+            );
+            tokio::join!(
+                async {
+                    let _b = locks.lock("a", 4, 12, false).await.unwrap();
+                    order.lock().unwrap().push("shared");
+                },
+                async {
+                    sleep(Duration::from_millis(20)).await;
+                    order.lock().unwrap().push("release");
+                    drop(held);
+                },
+            );
+        });
+        assert_eq!(*order.lock().unwrap(), vec!["release", "shared"]);
+    }
+
+    #[test]
+    pub fn test_lock_all() {
+        let locks = ChunkLocks::default();
+        let order = Mutex::new(vec![]);
+        runtime().block_on(async {
+            let held = locks.lock("a", 0, 8, false).await.unwrap();
+            tokio::join!(
+                async {
+                    let _all = locks.lock_all().await.unwrap();
+                    order.lock().unwrap().push("all");
+                    sleep(Duration::from_millis(20)).await;
+                    order.lock().unwrap().push("all released");
+                },
+                async {
+                    // requested after lock_all, waits for it
+                    sleep(Duration::from_millis(10)).await;
+                    let _b = locks.lock("b", 0, 8, false).await.unwrap();
+                    order.lock().unwrap().push("b");
+                },
+                async {
+                    sleep(Duration::from_millis(20)).await;
+                    order.lock().unwrap().push("a released");
+                    drop(held);
+                },
+            );
+        });
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec!["a released", "all", "all released", "b"]
+        );
+    }
+
+    #[test]
+    pub fn test_close() {
+        let locks = ChunkLocks::default();
+        let order = Mutex::new(vec![]);
+        runtime().block_on(async {
+            let held = locks.lock("a", 0, 8, true).await.unwrap();
+            tokio::join!(
+                async {
+                    locks.close().await;
+                    order.lock().unwrap().push("closed");
+                },
+                async {
+                    sleep(Duration::from_millis(10)).await;
+                    assert!(matches!(
+                        locks.lock("b", 0, 8, false).await,
+                        Err(EngineError::Closed)
+                    ));
+                    assert!(matches!(locks.lock_all().await, Err(EngineError::Closed)));
+                    order.lock().unwrap().push("release");
+                    drop(held);
+                },
+            );
+        });
+        assert_eq!(*order.lock().unwrap(), vec!["release", "closed"]);
+    }
+
+    #[test]
+    pub fn test_lock_many_with_lock_all() {
+        let locks = ChunkLocks::default();
+        let order = Mutex::new(vec![]);
+        runtime().block_on(async {
+            let held = locks.lock("b", 0, 4, true).await.unwrap();
+            let done = timeout(Duration::from_secs(1), async {
+                tokio::join!(
+                    // a rename of "a" to "b" waits for "b" without taking "a"
+                    async {
+                        let _ranges = locks
+                            .lock_many(&[("a", 0, u64::MAX, true), ("b", 0, u64::MAX, true)])
+                            .await
+                            .unwrap();
+                        order.lock().unwrap().push("rename");
+                    },
+                    async {
+                        sleep(Duration::from_millis(10)).await;
+                        drop(locks.lock("a", 0, 4, true).await.unwrap());
+                        let _all = locks.lock_all().await.unwrap();
+                        order.lock().unwrap().push("all");
+                    },
+                    async {
+                        sleep(Duration::from_millis(30)).await;
+                        drop(held);
+                    },
+                );
+            })
+            .await;
+            assert!(done.is_ok());
+        });
+        assert_eq!(*order.lock().unwrap(), vec!["all", "rename"]);
+    }
+}
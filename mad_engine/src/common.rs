@@ -2,10 +2,11 @@
 //!
 //! Atomicity is not tested
 
-use crate::{utils::*, DbEngine};
+use crate::utils::*;
 use async_spdk::blob::BlobId as SBlobId;
 use serde::{Deserialize, Serialize};
-use std::{cell::RefCell, collections::HashMap, sync::Arc};
+use std::collections::HashMap;
+use std::ops::Range;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChunkMeta {
@@ -14,27 +15,184 @@ pub struct ChunkMeta {
     // page -> (BlobId, offset)
     pub(crate) location: Option<HashMap<u64, PagePos>>,
     // checksum algorithm type
-    pub(crate) csum_type: String,
-    pub(crate) csum_data: Vec<u32>,
+    pub(crate) csum_type: ChecksumAlgo,
+    pub(crate) csum_data: Vec<u64>,
+    // compressed extents, extent id -> extent
+    #[serde(default)]
+    pub(crate) extents: HashMap<u64, Extent>,
+    // id given to the next compressed extent
+    #[serde(default)]
+    pub(crate) next_extent: u64,
+    // version of the data key, none if stored in plaintext
+    #[serde(default)]
+    pub(crate) key_version: Option<u32>,
+    // set if pages are Reed-Solomon striped instead of mapped one by one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) stripe: Option<StripeGeometry>,
+    // stripe index -> stripe, stripe i holds logical pages
+    // [i * data_shards, (i + 1) * data_shards)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) stripes: HashMap<u64, Stripe>,
+    // expected size in bytes, picks the allocation class of writes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) size_hint: Option<u64>,
 }
 
 // structure used for stat
+#[derive(Debug, Clone)]
 pub struct StatMeta {
     pub(crate) size: u64,
-    #[allow(unused)]
-    pub(crate) csum_type: String,
+    pub(crate) csum_type: ChecksumAlgo,
 }
 
 impl StatMeta {
     pub fn get_size(&self) -> u64 {
         self.size
     }
+
+    pub fn get_csum_type(&self) -> ChecksumAlgo {
+        self.csum_type
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PagePos {
+    // index of the blobstore holding the blob
+    #[serde(default)]
+    pub(crate) bs: u32,
     pub(crate) bid: SBlobId,
     pub(crate) offset: u64,
+    // set if the page is stored in a compressed extent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) extent: Option<u64>,
+    // mirrored copies, the position above is the primary copy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) copies: Vec<PageCopy>,
+}
+
+// a mirrored copy of a page
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct PageCopy {
+    pub(crate) bs: u32,
+    pub(crate) bid: SBlobId,
+    pub(crate) offset: u64,
+    // checksum of the stored bytes of this copy
+    pub(crate) csum: u64,
+}
+
+impl PagePos {
+    /// page at the first place, mirrored at the others
+    pub(crate) fn from_places(places: &[(BlobRef, u64)]) -> Self {
+        let (blob, offset) = places[0];
+        Self {
+            bs: blob.bs,
+            bid: blob.bid,
+            offset,
+            extent: None,
+            copies: places[1..]
+                .iter()
+                .map(|(b, o)| PageCopy {
+                    bs: b.bs,
+                    bid: b.bid,
+                    offset: *o,
+                    csum: 0,
+                })
+                .collect(),
+        }
+    }
+
+    /// blob holding the primary copy
+    pub(crate) fn blob(&self) -> BlobRef {
+        BlobRef {
+            bs: self.bs,
+            bid: self.bid,
+        }
+    }
+
+    /// positions of the primary copy and all mirrored copies
+    pub(crate) fn places(&self) -> Vec<(BlobRef, u64)> {
+        let mut ret = vec![(self.blob(), self.offset)];
+        ret.extend(self.copies.iter().map(|c| {
+            (
+                BlobRef {
+                    bs: c.bs,
+                    bid: c.bid,
+                },
+                c.offset,
+            )
+        }));
+        ret
+    }
+}
+
+/// XTS tweak derived from blob and page offset
+pub(crate) fn page_tweak(blob: BlobRef, offset: u64) -> u128 {
+    let bid_hash = Hasher::with_algo(ChecksumAlgo::XxHash64).checksum(blob.key().as_bytes());
+    ((bid_hash as u128) << 64) | offset as u128
+}
+
+// a blob on one of the configured blobstores
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(from = "BlobRefRepr")]
+pub struct BlobRef {
+    // index of the blobstore
+    pub(crate) bs: u32,
+    pub(crate) bid: SBlobId,
+}
+
+// blobs used to be stored as bare ids on the first blobstore
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlobRefRepr {
+    Plain(SBlobId),
+    Full { bs: u32, bid: SBlobId },
+}
+
+impl From<BlobRefRepr> for BlobRef {
+    fn from(r: BlobRefRepr) -> Self {
+        match r {
+            BlobRefRepr::Plain(bid) => Self { bs: 0, bid },
+            BlobRefRepr::Full { bs, bid } => Self { bs, bid },
+        }
+    }
+}
+
+impl BlobRef {
+    /// key in the global free list, blobs on the first blobstore keep
+    /// their bare id
+    pub(crate) fn key(&self) -> String {
+        if self.bs == 0 {
+            self.bid.to_string()
+        } else {
+            format!("{}:{}", self.bs, self.bid)
+        }
+    }
+}
+
+// a run of logical pages stored compressed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Extent {
+    // first logical page
+    pub(crate) first_page: u64,
+    // number of logical pages
+    pub(crate) pages: u64,
+    pub(crate) algo: CompressAlgo,
+    // compressed length in bytes
+    pub(crate) stored_len: u64,
+    // pages holding the compressed bytes
+    pub(crate) poses: Vec<PagePos>,
+    // checksum of each stored page
+    pub(crate) csum: Vec<u64>,
+    // logical pages still mapped to this extent
+    pub(crate) refs: u64,
+}
+
+// data pages of a stripe followed by its parity pages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Stripe {
+    pub(crate) shards: Vec<PagePos>,
+    // checksum of each stored shard
+    pub(crate) csum: Vec<u64>,
 }
 
 impl Default for ChunkMeta {
@@ -42,8 +200,14 @@ impl Default for ChunkMeta {
         Self {
             size: 0,
             location: None,
-            csum_type: "crc32".to_owned(),
+            csum_type: ChecksumAlgo::default(),
             csum_data: vec![],
+            extents: HashMap::new(),
+            next_extent: 0,
+            key_version: None,
+            stripe: None,
+            stripes: HashMap::new(),
+            size_hint: None,
         }
     }
 }
@@ -52,6 +216,96 @@ impl ChunkMeta {
     pub fn get_size(&self) -> u64 {
         self.size
     }
+
+    /// all pages occupied by this chunk
+    pub(crate) fn pages(&self) -> Vec<PagePos> {
+        let mut ret: Vec<PagePos> = match &self.location {
+            Some(loc) => loc
+                .values()
+                .filter(|pos| pos.extent.is_none())
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        for extent in self.extents.values() {
+            ret.extend(extent.poses.iter().cloned());
+        }
+        for stripe in self.stripes.values() {
+            ret.extend(stripe.shards.iter().cloned());
+        }
+        ret
+    }
+
+    /// mapped pages in `pages` and the runs they form, a run ends at a hole
+    /// or where the next logical page is not stored at the next offset of
+    /// the same blob
+    ///
+    /// pages of compressed extents are not counted
+    pub(crate) fn page_runs(&self, pages: Range<u64>) -> (u64, u64) {
+        let mut mapped: Vec<(u64, &PagePos)> = match &self.location {
+            Some(loc) => loc
+                .iter()
+                .filter(|(page, pos)| pages.contains(*page) && pos.extent.is_none())
+                .map(|(page, pos)| (*page, pos))
+                .collect(),
+            None => vec![],
+        };
+        mapped.sort_by_key(|(page, _)| *page);
+        let breaks = mapped
+            .windows(2)
+            .filter(|w| {
+                let ((prev_page, prev), (page, pos)) = (w[0], w[1]);
+                page != prev_page + 1 || pos.blob() != prev.blob() || pos.offset != prev.offset + 1
+            })
+            .count() as u64;
+        let runs = if mapped.is_empty() { 0 } else { breaks + 1 };
+        (mapped.len() as u64, runs)
+    }
+
+    /// ranges of consecutive mapped logical pages in `pages`, in order
+    ///
+    /// pages of compressed extents are not counted
+    pub(crate) fn mapped_ranges(&self, pages: Range<u64>) -> Vec<Range<u64>> {
+        let mut mapped: Vec<u64> = match &self.location {
+            Some(loc) => loc
+                .iter()
+                .filter(|(page, pos)| pages.contains(*page) && pos.extent.is_none())
+                .map(|(page, _)| *page)
+                .collect(),
+            None => vec![],
+        };
+        mapped.sort_unstable();
+        let mut ranges: Vec<Range<u64>> = vec![];
+        for page in mapped {
+            match ranges.last_mut() {
+                Some(last) if last.end == page => last.end += 1,
+                _ => ranges.push(page..page + 1),
+            }
+        }
+        ranges
+    }
+
+    /// unmap a logical page, return pages no longer used by this chunk
+    pub(crate) fn unmap_page(&mut self, page: u64) -> Vec<PagePos> {
+        let pos = match self.location.as_mut().and_then(|l| l.remove(&page)) {
+            Some(pos) => pos,
+            None => return vec![],
+        };
+        match pos.extent {
+            None => vec![pos],
+            Some(id) => match self.extents.get_mut(&id) {
+                Some(extent) => {
+                    extent.refs -= 1;
+                    if extent.refs == 0 {
+                        self.extents.remove(&id).unwrap().poses
+                    } else {
+                        vec![]
+                    }
+                }
+                None => vec![],
+            },
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -59,12 +313,18 @@ pub struct MadEngine {
     // global free list
     // pub(crate) free_list: HashMap<SBlobId, BitMap>,
     pub(crate) free_list: HashMap<String, BitMap>,
-    // allocated blobs
-    pub(crate) blobs: Vec<SBlobId>,
+    // allocated blobs, group by group, `blobs_per_group` blobs per group
+    pub(crate) blobs: Vec<BlobRef>,
+    // blobs of each group, at least the number of allocator workers
+    #[serde(default = "default_blobs_per_group")]
+    pub(crate) blobs_per_group: usize,
     // information about lower device
     pub(crate) device: DeviceInfo,
     // thread local blob size
     pub(crate) blob_size: u64,
+    // blobs added to allocation classes, the blobs above are small ones
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) pools: HashMap<SizeClass, ClassPool>,
 }
 
 unsafe impl Send for MadEngine {}
@@ -75,16 +335,55 @@ impl MadEngine {
         Self {
             free_list: HashMap::new(),
             blobs: Vec::new(),
+            blobs_per_group: NUM_THREAD,
             device: DeviceInfo::new(total_cluster),
             blob_size: init_blob_size,
+            pools: HashMap::new(),
         }
     }
 
+    /// allocation class of a blob, blobs created on open are small
+    pub(crate) fn blob_class(&self, blob: &BlobRef) -> SizeClass {
+        self.pools
+            .iter()
+            .find(|(_, pool)| pool.groups.iter().flatten().any(|b| b == blob))
+            .map_or(SizeClass::Small, |(class, _)| *class)
+    }
+
     pub fn set_total_cluster(&mut self, total_cluster: u64) {
         self.device.cluster_size = total_cluster;
     }
 }
 
+// groups used to have one blob per thread of a fixed size pool
+fn default_blobs_per_group() -> usize {
+    NUM_THREAD
+}
+
+// allocation class of a write, classes are kept on different blobs so
+// small overwrites do not fragment large sequential chunks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SizeClass {
+    Small,
+    Large,
+}
+
+// blobs added to an allocation class after open
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ClassPool {
+    // blob j of a group belongs to allocator worker j % #workers
+    pub(crate) groups: Vec<Vec<BlobRef>>,
+}
+
+/// Growth policy of an allocation class
+#[derive(Debug, Clone, Copy)]
+pub struct ClassPolicy {
+    // clusters of each blob added to the class
+    pub(crate) blob_size: u64,
+    // groups the class may add, 0 to never grow
+    pub(crate) max_groups: usize,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DeviceInfo {
     // cluster size in MB
@@ -107,14 +406,16 @@ impl DeviceInfo {
     }
 }
 
+/// Allocator state of one `AllocPool` worker
 pub struct ThreadData {
     // allocated blobs in the thread
-    pub(crate) tblobs: Vec<SBlobId>,
+    pub(crate) tblobs: Vec<BlobRef>,
     // self owned free list, can 'steal' others' space
-    pub(crate) tfree_list: HashMap<SBlobId, BitMap>,
+    pub(crate) tfree_list: HashMap<BlobRef, BitMap>,
+    // allocation class of each allocated blob
+    pub(crate) tclasses: HashMap<BlobRef, SizeClass>,
     // channel: Option<IoChannel>,
     // pub(crate) db: Option<Arc<RocksdbEngine>>,
-    pub(crate) db: Option<Arc<DbEngine>>,
     // bs: Option<Arc<Blobstore>>,
     // handle: Option<Arc<DeviceEngine>>,
 }
@@ -124,16 +425,24 @@ impl Default for ThreadData {
         Self {
             tblobs: Vec::new(),
             tfree_list: HashMap::new(),
+            tclasses: HashMap::new(),
             // channel: None,
-            db: None,
             // bs: None,
             // handle: None,
         }
     }
 }
 
-thread_local! {
-    pub static TLS: RefCell<ThreadData> = RefCell::new(ThreadData::default());
+impl ThreadData {
+    /// free pages on the owned blobs of a class
+    pub(crate) fn class_free(&self, class: SizeClass) -> u64 {
+        self.tblobs
+            .iter()
+            .filter(|blob| self.tclasses.get(blob) == Some(&class))
+            .filter_map(|blob| self.tfree_list.get(blob))
+            .map(|bm| bm.free_count())
+            .sum()
+    }
 }
 
 pub struct FsInfo {
@@ -163,3 +472,53 @@ impl FsInfo {
         self.cluster_free
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pos(bs: u32, offset: u64) -> PagePos {
+        let blob = BlobRef {
+            bs,
+            bid: SBlobId::default(),
+        };
+        PagePos::from_places(&[(blob, offset)])
+    }
+
+    #[test]
+    pub fn test_page_runs() {
+        let mut meta = ChunkMeta::default();
+        assert_eq!(meta.page_runs(0..u64::MAX), (0, 0));
+        assert!(meta.mapped_ranges(0..u64::MAX).is_empty());
+
+        let mut location = HashMap::new();
+        for page in 0..4 {
+            location.insert(page, pos(0, 10 + page));
+        }
+        // moved to another blob
+        location.insert(4, pos(1, 5));
+        // a hole at page 5 ends the run, though page 6 is stored right
+        // after page 4
+        location.insert(6, pos(1, 6));
+        location.insert(7, pos(1, 7));
+        // pages of compressed extents are not counted
+        location.insert(
+            8,
+            PagePos {
+                extent: Some(0),
+                ..pos(0, 30)
+            },
+        );
+        location.insert(9, pos(0, 0));
+        meta.location = Some(location);
+
+        assert_eq!(meta.page_runs(0..u64::MAX), (8, 4));
+        assert_eq!(meta.page_runs(0..4), (4, 1));
+        assert_eq!(meta.page_runs(2..7), (4, 3));
+        assert_eq!(meta.page_runs(4..8), (3, 2));
+        assert_eq!(meta.page_runs(6..8), (2, 1));
+        assert_eq!(meta.page_runs(5..6), (0, 0));
+        assert_eq!(meta.mapped_ranges(0..u64::MAX), vec![0..5, 6..8, 9..10]);
+        assert_eq!(meta.mapped_ranges(3..7), vec![3..5, 6..7]);
+    }
+}
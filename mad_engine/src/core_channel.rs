@@ -0,0 +1,233 @@
+//! This module sends typed requests to the reactor of a core
+//!
+//! A request is boxed together with its completion slot and handed to a
+//! single trampoline, which serves it on the reactor and fills the slot.
+//! Requests own everything they use, so a caller may drop or leak the
+//! waiting future at any time
+
+use crate::error::Result;
+use async_spdk::event::SpdkEvent;
+use std::{
+    cell::Cell,
+    ffi::c_void,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Wake, Waker},
+};
+use tokio::sync::Notify;
+
+thread_local! {
+    // core of the reactor running on this thread, set by the trampoline
+    static REACTOR_CORE: Cell<Option<u32>> = Cell::new(None);
+}
+
+/// A request served on the reactor of a core
+///
+/// a request owns its buffers, they are handed back in the response, which
+/// crosses back to the caller's thread
+pub trait Request: 'static {
+    type Response: Send + 'static;
+
+    /// serve the request, this is called on the reactor
+    fn serve(self) -> Pin<Box<dyn Future<Output = Self::Response>>>;
+}
+
+/// A value only used on the reactor of one core, such as a blobstore
+pub struct CoreBound<T> {
+    core: u32,
+    value: T,
+}
+
+// SAFETY: `value` is only reachable through `get`, which hands it out on
+// the reactor of `core` only, so it is never used from two threads; moving
+// or sharing the wrapper itself does not touch `value`, and `new` requires
+// that dropping `value` on any thread is sound
+unsafe impl<T> Send for CoreBound<T> {}
+unsafe impl<T> Sync for CoreBound<T> {}
+
+impl<T> CoreBound<T> {
+    /// bind `value` to the reactor of `core`
+    ///
+    /// # Safety
+    ///
+    /// dropping `value` on a thread other than the reactor must be sound
+    pub unsafe fn new(core: u32, value: T) -> Self {
+        Self { core, value }
+    }
+
+    /// core whose reactor may use the value
+    pub fn core(&self) -> u32 {
+        self.core
+    }
+
+    /// the value, none unless called while serving a request on the
+    /// reactor of its core
+    pub fn get(&self) -> Option<&T> {
+        match REACTOR_CORE.with(|c| c.get()) {
+            Some(core) if core == self.core => Some(&self.value),
+            _ => None,
+        }
+    }
+}
+
+/// Typed submission queue to the reactor of one core
+#[derive(Debug, Clone, Copy)]
+pub struct CoreChannel {
+    core: u32,
+}
+
+impl CoreChannel {
+    pub fn new(core: u32) -> Self {
+        Self { core }
+    }
+
+    /// core whose reactor serves the requests
+    pub fn core(&self) -> u32 {
+        self.core
+    }
+
+    /// serve a request on the reactor and wait for its response
+    ///
+    /// if the returned future is dropped before the response arrives, the
+    /// response is dropped once the reactor is done with the request
+    pub async fn call<R: Request>(&self, req: R) -> Result<R::Response> {
+        let done = Arc::new(Completion::new());
+        let envelope = Box::into_raw(Box::new(Envelope {
+            core: self.core,
+            req,
+            done: done.clone(),
+        }));
+        let e = match SpdkEvent::alloc(
+            self.core,
+            Self::trampoline::<R> as *const () as *mut c_void,
+            envelope as *mut c_void,
+        ) {
+            Ok(e) => e,
+            Err(err) => {
+                drop(unsafe { Box::from_raw(envelope) });
+                return Err(err.into());
+            }
+        };
+        if let Err(err) = e.call() {
+            // SAFETY: the event is not sent, so the trampoline never takes
+            // the envelope
+            drop(unsafe { Box::from_raw(envelope) });
+            return Err(err.into());
+        }
+        Ok(done.wait().await)
+    }
+
+    /// entry of every event sent by `call`, `R` is the type the envelope
+    /// was boxed with
+    fn trampoline<R: Request>(arg: *mut c_void) {
+        // SAFETY: `arg` is the envelope boxed by `call` for this event,
+        // which runs once
+        let envelope = unsafe { *Box::from_raw(arg as *mut Envelope<R>) };
+        let Envelope { core, req, done } = envelope;
+        REACTOR_CORE.with(|c| c.set(Some(core)));
+        ReactorTask::spawn(async move {
+            let resp = req.serve().await;
+            done.complete(resp);
+        });
+    }
+}
+
+struct Envelope<R: Request> {
+    // core of the reactor the event is sent to
+    core: u32,
+    req: R,
+    done: Arc<Completion<R::Response>>,
+}
+
+/// Completion slot of a request
+struct Completion<T> {
+    // response not taken yet
+    resp: Mutex<Option<T>>,
+    notify: Notify,
+}
+
+impl<T> Completion<T> {
+    fn new() -> Self {
+        Self {
+            resp: Mutex::new(None),
+            notify: Notify::new(),
+        }
+    }
+
+    /// store the response and wake the waiter
+    fn complete(&self, resp: T) {
+        *self.resp.lock().unwrap() = Some(resp);
+        self.notify.notify_one();
+    }
+
+    /// wait for the response
+    async fn wait(&self) -> T {
+        loop {
+            let resp = self.resp.lock().unwrap().take();
+            if let Some(resp) = resp {
+                return resp;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// A future driven on the reactor thread that spawned it
+///
+/// SPDK completes an operation on the thread that submitted it, so the
+/// waker polls the future again right there
+struct ReactorTask {
+    fut: Mutex<Option<Pin<Box<dyn Future<Output = ()>>>>>,
+    woken: AtomicBool,
+}
+
+// SAFETY: the future is only polled by `run`, which SPDK completions call
+// on the reactor thread that spawned the task; other threads only hold the
+// `Arc` through the waker and never poll
+unsafe impl Send for ReactorTask {}
+unsafe impl Sync for ReactorTask {}
+
+impl ReactorTask {
+    /// run `fut` on the current reactor
+    fn spawn(fut: impl Future<Output = ()> + 'static) {
+        let task = Arc::new(Self {
+            fut: Mutex::new(Some(Box::pin(fut))),
+            woken: AtomicBool::new(true),
+        });
+        task.run();
+    }
+
+    fn run(self: &Arc<Self>) {
+        let waker = Waker::from(self.clone());
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            // a wake while polling is picked up by the poller in this loop
+            let mut fut = match self.fut.try_lock() {
+                Ok(fut) => fut,
+                Err(_) => return,
+            };
+            if !self.woken.swap(false, Ordering::SeqCst) {
+                return;
+            }
+            let ready = match fut.as_mut() {
+                Some(f) => f.as_mut().poll(&mut cx).is_ready(),
+                None => return,
+            };
+            if ready {
+                *fut = None;
+                return;
+            }
+        }
+    }
+}
+
+impl Wake for ReactorTask {
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        self.run();
+    }
+}
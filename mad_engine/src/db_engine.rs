@@ -1,13 +1,23 @@
 //! RocksdbEngine implementation
 
 use crate::error::Result;
-use rocksdb::{Options, DB};
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch,
+    WriteOptions, DB,
+};
 use std::ffi::c_void;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use async_spdk::blobfs::SpdkFilesystem;
 
+/// column family holding every `ChunkMeta` record, keyed by chunk name
+pub const META_CF_NAME: &str = "chunk_meta_cf";
+/// column family holding chunk extended attributes, keyed by `name\0attr`
+pub const XATTR_CF_NAME: &str = "chunk_xattr_cf";
+/// column family holding journaled chunk writes, keyed by `name\0seq`
+pub const JOURNAL_CF_NAME: &str = "chunk_journal_cf";
+
 pub struct DbEngine {
     pub db: DB,
     pub db_opts: Options,
@@ -37,6 +47,7 @@ fn rocksdb_options(
         .expect("fail to initilize spdk env")
     };
     opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
     opts.set_env(&env);
     opts
 }
@@ -59,11 +70,38 @@ impl DbEngine {
             bdev,
             cache_size_in_mb,
         );
-        let db = DB::open(&opts, data_path)?;
+        let db = DB::open_cf_descriptors(
+            &opts,
+            data_path,
+            vec![
+                ColumnFamilyDescriptor::new(rocksdb::DEFAULT_COLUMN_FAMILY_NAME, opts.clone()),
+                ColumnFamilyDescriptor::new(META_CF_NAME, opts.clone()),
+                ColumnFamilyDescriptor::new(XATTR_CF_NAME, opts.clone()),
+                ColumnFamilyDescriptor::new(JOURNAL_CF_NAME, opts.clone()),
+            ],
+        )?;
         let db_engine = DbEngine { db, db_opts: opts };
         Ok(db_engine)
     }
 
+    fn meta_cf(&self) -> &ColumnFamily {
+        self.db
+            .cf_handle(META_CF_NAME)
+            .expect("chunk metadata column family should be opened")
+    }
+
+    fn xattr_cf(&self) -> &ColumnFamily {
+        self.db
+            .cf_handle(XATTR_CF_NAME)
+            .expect("chunk xattr column family should be opened")
+    }
+
+    fn journal_cf(&self) -> &ColumnFamily {
+        self.db
+            .cf_handle(JOURNAL_CF_NAME)
+            .expect("chunk journal column family should be opened")
+    }
+
     pub fn put<K, V>(&self, key: K, value: V) -> Result<()>
     where
         K: AsRef<[u8]>,
@@ -82,4 +120,318 @@ impl DbEngine {
         self.db.delete(key)?;
         Ok(())
     }
+
+    /// Put a chunk's metadata
+    pub fn put_meta<K, V>(&self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.db.put_cf(self.meta_cf(), key, value)?;
+        Ok(())
+    }
+
+    /// Get a chunk's metadata
+    pub fn get_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        let ret = self.db.get_cf(self.meta_cf(), key)?;
+        Ok(ret)
+    }
+
+    /// Delete a chunk's metadata
+    pub fn delete_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        self.db.delete_cf(self.meta_cf(), key)?;
+        Ok(())
+    }
+
+    /// Put a chunk's metadata into a write batch
+    pub fn batch_put_meta<K, V>(&self, batch: &mut WriteBatch, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        batch.put_cf(self.meta_cf(), key, value);
+    }
+
+    /// Delete a chunk's metadata in a write batch
+    pub fn batch_delete_meta<K: AsRef<[u8]>>(&self, batch: &mut WriteBatch, key: K) {
+        batch.delete_cf(self.meta_cf(), key);
+    }
+
+    /// Flush memtables of every column family
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        self.db.flush_cf(self.meta_cf())?;
+        self.db.flush_cf(self.xattr_cf())?;
+        self.db.flush_cf(self.journal_cf())?;
+        Ok(())
+    }
+
+    /// Apply a write batch atomically
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// Scan chunk metadata in key order
+    ///
+    /// Start from `from` (inclusive), stop at the first key without `prefix`
+    /// or once `f` returns false
+    pub fn scan_meta<F>(&self, prefix: &[u8], from: &[u8], mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        let iter = self
+            .db
+            .iterator_cf(self.meta_cf(), IteratorMode::From(from, Direction::Forward));
+        for item in iter {
+            let (k, v) = item?;
+            if !k.starts_with(prefix) || !f(&k, &v)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Put an extended attribute of a chunk
+    pub fn put_xattr(&self, name: &str, attr: &str, value: &[u8]) -> Result<()> {
+        self.db
+            .put_cf(self.xattr_cf(), xattr_key(name, attr), value)?;
+        Ok(())
+    }
+
+    /// Get an extended attribute of a chunk
+    pub fn get_xattr(&self, name: &str, attr: &str) -> Result<Option<Vec<u8>>> {
+        let ret = self.db.get_cf(self.xattr_cf(), xattr_key(name, attr))?;
+        Ok(ret)
+    }
+
+    /// Delete an extended attribute of a chunk
+    pub fn delete_xattr(&self, name: &str, attr: &str) -> Result<()> {
+        self.db.delete_cf(self.xattr_cf(), xattr_key(name, attr))?;
+        Ok(())
+    }
+
+    /// Get all extended attributes of a chunk, in attribute name order
+    pub fn list_xattr(&self, name: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let prefix = xattr_key(name, "");
+        let mut ret = vec![];
+        let iter = self.db.iterator_cf(
+            self.xattr_cf(),
+            IteratorMode::From(&prefix, Direction::Forward),
+        );
+        for item in iter {
+            let (k, v) = item?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            ret.push((
+                String::from_utf8_lossy(&k[prefix.len()..]).into_owned(),
+                v.into_vec(),
+            ));
+        }
+        Ok(ret)
+    }
+
+    /// Delete all extended attributes of a chunk in a write batch
+    pub fn batch_delete_xattrs(&self, batch: &mut WriteBatch, name: &str) -> Result<()> {
+        for (attr, _) in self.list_xattr(name)? {
+            batch.delete_cf(self.xattr_cf(), xattr_key(name, &attr));
+        }
+        Ok(())
+    }
+
+    /// Move all extended attributes of a chunk to another name in a write batch
+    pub fn batch_move_xattrs(&self, batch: &mut WriteBatch, old: &str, new: &str) -> Result<()> {
+        for (attr, value) in self.list_xattr(old)? {
+            let (from, to) = renamed_key(old, new, |name| xattr_key(name, &attr));
+            batch.delete_cf(self.xattr_cf(), from);
+            batch.put_cf(self.xattr_cf(), to, value);
+        }
+        Ok(())
+    }
+
+    /// Put a journal record of a chunk, the RocksDB WAL is synced
+    /// before it returns
+    pub fn put_journal(&self, name: &str, seq: u64, record: &[u8]) -> Result<()> {
+        let mut opts = WriteOptions::default();
+        opts.set_sync(true);
+        self.db
+            .put_cf_opt(self.journal_cf(), journal_key(name, seq), record, &opts)?;
+        Ok(())
+    }
+
+    /// List journal records of a chunk in sequence order
+    pub fn list_journal(&self, name: &str) -> Result<Vec<(u64, Vec<u8>)>> {
+        let mut prefix = name.as_bytes().to_vec();
+        prefix.push(0);
+        let mut ret = vec![];
+        let iter = self.db.iterator_cf(
+            self.journal_cf(),
+            IteratorMode::From(&prefix, Direction::Forward),
+        );
+        for item in iter {
+            let (k, v) = item?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            if let Some((_, seq)) = split_journal_key(&k) {
+                ret.push((seq, v.into_vec()));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// List names of chunks having journal records
+    pub fn journaled_chunks(&self) -> Result<Vec<String>> {
+        let mut ret: Vec<String> = vec![];
+        for item in self.db.iterator_cf(self.journal_cf(), IteratorMode::Start) {
+            let (k, _) = item?;
+            if let Some((name, _)) = split_journal_key(&k) {
+                if ret.last() != Some(&name) {
+                    ret.push(name);
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Delete a journal record of a chunk in a write batch
+    pub fn batch_delete_journal(&self, batch: &mut WriteBatch, name: &str, seq: u64) {
+        batch.delete_cf(self.journal_cf(), journal_key(name, seq));
+    }
+
+    /// Delete all journal records of a chunk in a write batch
+    pub fn batch_delete_journals(&self, batch: &mut WriteBatch, name: &str) -> Result<()> {
+        for (seq, _) in self.list_journal(name)? {
+            batch.delete_cf(self.journal_cf(), journal_key(name, seq));
+        }
+        Ok(())
+    }
+
+    /// Move all journal records of a chunk to another name in a write batch
+    pub fn batch_move_journals(&self, batch: &mut WriteBatch, old: &str, new: &str) -> Result<()> {
+        for (seq, record) in self.list_journal(old)? {
+            let (from, to) = renamed_key(old, new, |name| journal_key(name, seq));
+            batch.delete_cf(self.journal_cf(), from);
+            batch.put_cf(self.journal_cf(), to, record);
+        }
+        Ok(())
+    }
+
+    /// Move records that still live in the default keyspace into the chunk
+    /// metadata column family, `is_meta` picks which records to move
+    ///
+    /// Metadata written before the column family existed is migrated here
+    pub fn migrate_meta<F>(&self, is_meta: F) -> Result<usize>
+    where
+        F: Fn(&[u8], &[u8]) -> bool,
+    {
+        let mut batch = WriteBatch::default();
+        for item in self.db.iterator(IteratorMode::Start) {
+            let (k, v) = item?;
+            if is_meta(&k, &v) {
+                batch.put_cf(self.meta_cf(), &k, &v);
+                batch.delete(&k);
+            }
+        }
+        let moved = batch.len() / 2;
+        if moved > 0 {
+            self.db.write(batch)?;
+        }
+        Ok(moved)
+    }
+}
+
+/// chunk name and attribute name are separated by a NUL byte
+fn xattr_key(name: &str, attr: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(name.len() + attr.len() + 1);
+    key.extend_from_slice(name.as_bytes());
+    key.push(0);
+    key.extend_from_slice(attr.as_bytes());
+    key
+}
+
+/// chunk name and big-endian sequence number are separated by a NUL byte
+fn journal_key(name: &str, seq: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(name.len() + 9);
+    key.extend_from_slice(name.as_bytes());
+    key.push(0);
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+/// key of a record under the old and the new chunk name, `key` builds it
+/// from a chunk name
+fn renamed_key(old: &str, new: &str, key: impl Fn(&str) -> Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+    (key(old), key(new))
+}
+
+fn split_journal_key(key: &[u8]) -> Option<(String, u64)> {
+    if key.len() < 9 || key[key.len() - 9] != 0 {
+        return None;
+    }
+    let (name, seq) = key.split_at(key.len() - 8);
+    Some((
+        String::from_utf8_lossy(&name[..name.len() - 1]).into_owned(),
+        u64::from_be_bytes(seq.try_into().unwrap()),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_keys() {
+        assert_eq!(xattr_key("a", "user.x"), b"a\0user.x");
+        // a chunk's prefix does not reach the keys of a longer name
+        let prefix = xattr_key("a", "");
+        assert!(xattr_key("a", "user.x").starts_with(&prefix));
+        assert!(!xattr_key("ab", "user.x").starts_with(&prefix));
+        assert!(!journal_key("ab", 1).starts_with(&prefix));
+
+        // records of a chunk are listed in sequence order
+        assert!(journal_key("a", 1) < journal_key("a", 256));
+        assert!(journal_key("a", u64::MAX) < journal_key("ab", 0));
+        assert_eq!(
+            split_journal_key(&journal_key("a", 7)),
+            Some(("a".to_string(), 7))
+        );
+        assert_eq!(
+            split_journal_key(&journal_key("", 7)),
+            Some((String::new(), 7))
+        );
+        assert_eq!(split_journal_key(b"a\x01\0\0\0\0\0\0\0\x07"), None);
+        assert_eq!(split_journal_key(b"short"), None);
+    }
+
+    #[test]
+    pub fn test_renamed_key() {
+        // attributes move out of the old chunk's prefix into the new one's,
+        // also when one name starts with the other
+        for (old, new) in [("a", "b"), ("a", "ab"), ("ab", "a")] {
+            let (from, to) = renamed_key(old, new, |name| xattr_key(name, "user.x"));
+            assert_eq!(from, xattr_key(old, "user.x"));
+            assert!(from.starts_with(&xattr_key(old, "")));
+            assert!(to.starts_with(&xattr_key(new, "")));
+            assert!(!to.starts_with(&xattr_key(old, "")));
+            assert_eq!(&to[xattr_key(new, "").len()..], b"user.x");
+        }
+
+        // journal records keep their sequence numbers and order
+        let moved: Vec<_> = [1u64, 2, 256]
+            .iter()
+            .map(|&seq| renamed_key("a", "ab", |name| journal_key(name, seq)).1)
+            .collect();
+        assert!(moved.windows(2).all(|w| w[0] < w[1]));
+        let split: Vec<_> = moved.iter().filter_map(|k| split_journal_key(k)).collect();
+        assert_eq!(
+            split,
+            vec![
+                ("ab".to_string(), 1),
+                ("ab".to_string(), 2),
+                ("ab".to_string(), 256)
+            ]
+        );
+    }
 }
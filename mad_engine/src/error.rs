@@ -7,12 +7,36 @@ pub enum EngineError {
     #[error("cannot init bs")]
     BsInitError,
 
+    #[error("fail to start {0}: {1}")]
+    StartFail(String, String),
+
+    #[error("timed out starting {0}")]
+    StartTimeout(String),
+
     #[error("chunk metadata not found")]
     MetaNotExist,
 
+    #[error("chunk metadata already exists")]
+    MetaExist,
+
+    #[error("extended attribute not found")]
+    XattrNotExist,
+
+    #[error("invalid extended attribute name: {0:?}")]
+    XattrNameErr(String),
+
+    #[error("invalid chunk name: {0:?}")]
+    ChunkNameErr(String),
+
     #[error("chunksum mismatch")]
     CheckSumErr,
 
+    #[error("fail to decompress extent")]
+    DecompressErr,
+
+    #[error("erasure code error: {0}")]
+    ErasureErr(String),
+
     #[error("read out of range")]
     ReadOutRange,
 
@@ -22,6 +46,9 @@ pub enum EngineError {
     #[error("restore fail")]
     RestoreFail,
 
+    #[error("no free page left")]
+    NoSpace,
+
     #[error("fail to get global metadata")]
     GlobalGetFail,
 
@@ -34,8 +61,29 @@ pub enum EngineError {
     #[error("spdk Error: {0}")]
     SPDKError(#[from] SpdkError),
 
+    #[error("blobstore {0} is not configured")]
+    BsNotExist(u32),
+
+    #[error("blobstore is used off its core {0}")]
+    WrongCore(u32),
+
+    #[error("{0} is not supported")]
+    Unsupported(&'static str),
+
     #[error("fail to get BlobEngine name")]
     BlobEngineNameError,
+
+    #[error("engine is closed")]
+    Closed,
+
+    #[error("engine is still in use")]
+    Busy,
+
+    #[error("fail to handle signals: {0}")]
+    SignalErr(std::io::Error),
+
+    #[error("key error: {0}")]
+    KeyErr(String),
 }
 
 pub type Result<T> = std::result::Result<T, EngineError>;
@@ -2,35 +2,105 @@
 
 use crate::common::*;
 // use crate::transactiondb_engine::*;
+use crate::alloc_pool::AllocPool;
 use crate::db_engine::*;
 use crate::error::{EngineError, Result};
+use crate::page_repair::PageRepairs;
+use crate::signal::shutdown_signal;
 use crate::utils::*;
+use crate::AppHandle;
 use crate::BlobEngine;
+use crate::BlobIo;
 use crate::BsBindOpts;
 use crate::EngineOpts;
+use crate::FileEngineOpts;
 use async_spdk::env::DmaBuf;
-use rusty_pool::ThreadPool;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use log::*;
+use rocksdb::WriteBatch;
 use std::time::Duration;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    future::Future,
     path::Path,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread::JoinHandle,
 };
 
 // TODO:
 const IO_SIZE: u64 = 512;
+// pages the defragmenter rewrites under one range lock
+const DEFRAG_WINDOW: u64 = 256;
+// how often queued page repairs are written
+const REPAIR_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct FileEngine {
     // db: Arc<RocksdbEngine>,
     db: Arc<DbEngine>,
-    blob_engine: Arc<BlobEngine>,
+    // one blob engine per blobstore, indexed by `PagePos::bs`
+    blob_engines: Vec<Arc<BlobEngine>>,
     mad_engine: Arc<Mutex<MadEngine>>,
-    pool: ThreadPool,
+    // allocator workers and their state
+    alloc: AllocPool,
     pub(crate) init_blob_size: u64,
+    // copies written for every page
+    replicas: usize,
+    // stripe geometry of newly created files, none for page mapping
+    erasure: Option<StripeGeometry>,
+    // verified plaintext pages keyed by primary position
+    page_cache: Option<PageCache<(BlobRef, u64)>>,
+    // journaled small writes not flushed yet
+    write_buffer: Arc<WriteBuffer>,
+    // sequence number of the next journal record
+    journal_seq: AtomicU64,
+    // range locks and metadata locks of chunks
+    locks: ChunkLocks,
+    // page I/Os in flight in the engine
+    io_depth: usize,
+    // one permit per page I/O in flight, only taken by the I/Os themselves
+    // so nested fan-outs share the limit and never wait on each other
+    io_slots: tokio::sync::Semaphore,
+    // checksum algorithm for newly created files
+    csum_algo: ChecksumAlgo,
+    // compression algorithm for aligned write extents
+    compress_algo: CompressAlgo,
+    // loaded data keys, key version -> cipher
+    keys: HashMap<u32, Xts>,
+    // key version to encrypt newly created files, none for plaintext
+    active_key: Option<u32>,
+    // new operations are refused once `close` or drop starts
+    closed: AtomicBool,
+    // held while shutting down, set once shutdown succeeded
+    shut_down: tokio::sync::Mutex<bool>,
+    // unloaded blobstores, indexed by blobstore
+    unloaded: tokio::sync::Mutex<Vec<bool>>,
+    // writes and size hints from this many bytes are large, 0 for one class
+    large_write: u64,
+    // growth policy of each allocation class
+    class_policy: HashMap<SizeClass, ClassPolicy>,
+    // held while an allocation class grows
+    growing: tokio::sync::Mutex<()>,
+    // foreground reads and writes so far, the defragmenter backs off when
+    // it moves
+    fg_ops: AtomicU64,
+    // bad page copies found by reads, written by the repairer
+    repairs: PageRepairs,
+    // stops the SPDK app, dropped after RocksDB is closed
+    app: Option<AppHandle>,
 }
 
 impl Drop for FileEngine {
-    fn drop(&mut self) {}
+    fn drop(&mut self) {
+        // shutting down needs the runtime the engine runs on, which may be
+        // the one dropping it, RocksDB is still closed and the SPDK app
+        // stopped when the fields are dropped
+        if !*self.shut_down.get_mut() {
+            error!("file engine is dropped without close, blobstores are not unloaded");
+        }
+    }
 }
 
 impl FileEngine {
@@ -47,24 +117,86 @@ impl FileEngine {
         cache_size_in_mb: u64,
         init_blob_size: u64,
         is_reload: bool,
-    ) -> Result<(Self, EngineOpts)> {
-        // Set SPDK opts
-        let mut opts = EngineOpts::default();
-        opts.set_blobfs(blobfs_dev);
+    ) -> Result<(Arc<Self>, EngineOpts)> {
+        let mut opts = FileEngineOpts::default();
+        opts.set_db_path(&path.as_ref().to_string_lossy());
+        opts.set_config_file(config_file);
         opts.set_reactor_mask(reactor_mask);
+        opts.set_blobfs(blobfs_dev);
         opts.set_blobstore(vec![BsBindOpts {
             bdev_name: bs_dev.to_string(),
             core: bs_core,
         }]);
-        opts.set_config_file(config_file.clone());
         opts.set_name(app_name);
+        opts.set_cache_size(cache_size_in_mb);
+        opts.set_blob_size(init_blob_size);
+        Self::open(opts, is_reload).await
+    }
+
+    /// get a file engine handle by options, may use several blobstores
+    ///
+    /// a background flusher is started if writes are buffered or deferred
+    pub async fn open(fopts: FileEngineOpts, is_reload: bool) -> Result<(Arc<Self>, EngineOpts)> {
+        // data keys are checked before anything starts
+        let mut keys = HashMap::new();
+        for (version, key_file) in fopts.key_files.iter() {
+            let key = std::fs::read(key_file).map_err(|e| {
+                EngineError::KeyErr(format!("fail to read {}: {}", key_file.display(), e))
+            })?;
+            keys.insert(*version, Xts::new(&key)?);
+        }
+        if let Some(v) = fopts.active_key {
+            if !keys.contains_key(&v) {
+                return Err(EngineError::KeyErr(format!("key version {} not added", v)));
+            }
+        }
+
+        // Set SPDK opts
+        let mut opts = EngineOpts::default();
+        opts.set_blobfs(&fopts.blobfs_bdev);
+        opts.set_reactor_mask(&fopts.reactor_mask);
+        opts.set_blobstore(fopts.blobstores.clone());
+        opts.set_config_file(fopts.config_file.clone());
+        opts.set_name(&fopts.app_name);
 
         // Start SPDK environment
         opts.start_spdk(is_reload);
 
         // Wait for blobfs and blobstore establishing
-        opts.ready();
+        // a failed startup unloads what it started and stops the app on its
+        // own, one that timed out may never return
+        if let Err(e) = opts.ready(fopts.start_timeout).await {
+            error!("fail to start SPDK environment: {}", e);
+            if let EngineError::StartTimeout(_) = e {
+                opts.unload_started(fopts.start_timeout).await;
+                opts.detach();
+            } else {
+                opts.finish();
+            }
+            return Err(e);
+        }
+
+        // every step after this point gives the blobstores back and stops
+        // the app when it fails, so `EngineOpts` can be dropped
+        let start_timeout = fopts.start_timeout;
+        match Self::start(fopts, keys, &opts, is_reload).await {
+            Ok(engine) => Ok((engine, opts)),
+            Err(e) => {
+                error!("fail to open file engine: {}", e);
+                opts.unload_started(start_timeout).await;
+                opts.finish();
+                Err(e)
+            }
+        }
+    }
 
+    /// build the engine on a started SPDK environment
+    async fn start(
+        fopts: FileEngineOpts,
+        keys: HashMap<u32, Xts>,
+        opts: &EngineOpts,
+        is_reload: bool,
+    ) -> Result<Arc<Self>> {
         // Build TransactionDB
         // let db = Arc::new(RocksdbEngine::new(
         //     opts.fs.clone(),
@@ -77,413 +209,693 @@ impl FileEngine {
         let db = Arc::new(DbEngine::new(
             opts.fs.clone(),
             0,
-            path,
-            &config_file,
-            blobfs_dev,
-            cache_size_in_mb,
+            &fopts.db_path,
+            &fopts.config_file,
+            &fopts.blobfs_bdev,
+            fopts.cache_size_in_mb,
         )?);
 
-        let be = Arc::new(opts.create_be());
+        let bes: Vec<Arc<BlobEngine>> = opts.create_bes().into_iter().map(Arc::new).collect();
+        let init_blob_size = fopts.init_blob_size;
+        let replicas = fopts.replicas.max(1);
+        // a stripe needs one blob per shard
+        let groups = replicas.max(fopts.erasure.map_or(1, |geo| geo.total_shards()));
+        if groups > bes.len() {
+            warn!(
+                "{} blob groups share {} blobstores, losing a device may lose data",
+                groups,
+                bes.len()
+            );
+        }
 
-        let pool = ThreadPool::new(NUM_THREAD, NUM_THREAD, Duration::from_secs(1));
-        let num_init = NUM_THREAD;
-        if !is_reload {
+        let alloc = AllocPool::new(fopts.alloc_workers, &fopts.alloc_cores);
+        let workers = alloc.states().len();
+        let mut global_meta = if !is_reload {
             // TODO: finish cluster count API
-            let mad_engine = Arc::new(Mutex::new(MadEngine::new(256, init_blob_size)));
-            for _ in 0..num_init {
-                let handle = be.clone();
-                let blob_id = handle.create_blob().await?;
-                let blob = handle.open_blob(blob_id).await?;
-                handle.resize_blob(blob, init_blob_size).await?;
-                handle.sync_blob(blob).await?;
-                handle.close_blob(blob).await?;
-
-                let db = db.clone();
-                let me = mad_engine.clone();
-
-                // do the initialization work for each thread
-                pool.spawn(async move {
-                    TLS.with(move |f| {
-                        let mut br = f.borrow_mut();
-                        br.tblobs = vec![blob_id];
-                        br.tfree_list = HashMap::new();
-                        let bitmap = BitMap::new(init_blob_size * CLUSTER_SIZE);
-                        br.tfree_list.insert(blob_id, bitmap.clone());
-                        {
-                            let mut l = me.lock().unwrap();
-                            l.blobs.push(blob_id);
-                            l.free_list.insert(blob_id.clone().to_string(), bitmap);
-                        }
-                        br.db = Some(db);
-                    });
-                });
+            let mut global_meta = MadEngine::new(256, init_blob_size);
+            global_meta.blobs_per_group = workers;
+            global_meta
+        } else {
+            let global = db
+                .get(Hasher::new().checksum(MAGIC.as_bytes()).to_string())?
+                .ok_or(EngineError::RestoreFail)?;
+            let mut global_meta: MadEngine =
+                serde_json::from_slice(&global).map_err(|_| EngineError::RestoreFail)?;
+            // chunk metadata used to share the default keyspace with global metadata
+            let global_key = Hasher::new().checksum(MAGIC.as_bytes()).to_string();
+            let moved = db.migrate_meta(|k, v| {
+                k != global_key.as_bytes() && serde_json::from_slice::<ChunkMeta>(v).is_ok()
+            })?;
+            if moved > 0 {
+                info!("migrate {} chunk metadata records", moved);
             }
-            pool.join();
-            let global = {
-                let magic = mad_engine.lock().unwrap();
-                magic.clone()
-            };
+            // worker free lists are rebuilt from the global ones below
+            if Self::reconcile_free_lists(&db, &mut global_meta)? {
+                db.put(
+                    Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
+                    serde_json::to_string(&global_meta)?.as_bytes(),
+                )?;
+            }
+            global_meta
+        };
 
+        // each worker owns at least one blob per group, group g is put on
+        // blobstore g % #blobstores, groups missing after reload, and blobs
+        // missing for more workers than before, are created here
+        let old_per_group = global_meta.blobs_per_group;
+        let per_group = old_per_group.max(workers);
+        let old_groups = global_meta.blobs.len() / old_per_group;
+        let mut blobs = vec![];
+        let mut created = false;
+        for g in 0..old_groups.max(groups) {
+            let old: &[BlobRef] = if g < old_groups {
+                &global_meta.blobs[g * old_per_group..(g + 1) * old_per_group]
+            } else {
+                &[]
+            };
+            blobs.extend_from_slice(old);
+            let bs = old.first().map_or((g % bes.len()) as u32, |b| b.bs);
+            for _ in old.len()..per_group {
+                let blob = Self::create_blob(&bes[bs as usize], bs, init_blob_size).await?;
+                blobs.push(blob);
+                global_meta
+                    .free_list
+                    .insert(blob.key(), BitMap::new(init_blob_size * CLUSTER_SIZE));
+                created = true;
+            }
+        }
+        global_meta.blobs = blobs;
+        global_meta.blobs_per_group = per_group;
+        if created {
             db.put(
                 Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
-                serde_json::to_string(&global).unwrap().as_bytes(),
+                serde_json::to_string(&global_meta)?.as_bytes(),
             )?;
+        }
 
-            Ok((
-                Self {
-                    db,
-                    blob_engine: be,
-                    mad_engine,
-                    pool,
-                    init_blob_size,
-                },
-                opts,
-            ))
-        } else {
-            let global = db
-                .get(Hasher::new().checksum(MAGIC.as_bytes()).to_string())
-                .unwrap();
-            if global.is_none() {
-                return Err(EngineError::RestoreFail);
-            }
-            let global_meta: MadEngine =
-                serde_json::from_slice(String::from_utf8(global.unwrap()).unwrap().as_bytes())
-                    .unwrap();
-            let mad_engine = Arc::new(Mutex::new(global_meta));
-            let num_blobs = {
-                let l = mad_engine.lock().unwrap();
-                l.blobs.len()
-            };
-            for i in 0..num_init {
-                let db = db.clone();
-                let me = mad_engine.clone();
-                let mut thread_blobids = vec![];
-                let mut blob2map = HashMap::new();
-                {
-                    // let l = me.lock().unwrap();
-                    let mut id = i;
-                    while id < num_blobs {
-                        thread_blobids.push(me.lock().unwrap().blobs[id]);
-                        blob2map.insert(
-                            me.lock().unwrap().blobs[id],
-                            me.lock()
-                                .unwrap()
-                                .free_list
-                                .get(&me.lock().unwrap().blobs[id].to_string())
-                                .unwrap()
-                                .clone(),
-                        );
-                        id += num_init;
+        Self::assign_thread_blobs(&alloc, &global_meta);
+        let mad_engine = Arc::new(Mutex::new(global_meta));
+
+        // journaled writes not flushed before the last shutdown are buffered
+        // again
+        let mut write_buffer = match fopts.write_buffer {
+            Some((threshold, interval)) => WriteBuffer::new(true, threshold, interval),
+            None => WriteBuffer::new(false, 0, Duration::from_secs(1)),
+        };
+        if let Some(small_write) = fopts.deferred_apply {
+            write_buffer.set_deferred(small_write);
+        }
+        let flusher = fopts.write_buffer.is_some() || fopts.deferred_apply.is_some();
+        let mut journal_seq = 0;
+        for name in db.journaled_chunks()? {
+            for (seq, record) in db.list_journal(&name)? {
+                let record: JournalRecord =
+                    bincode::deserialize(&record).map_err(|_| EngineError::RestoreFail)?;
+                write_buffer.insert(&name, seq, record.offset, &record.data);
+                journal_seq = journal_seq.max(seq + 1);
+            }
+        }
+
+        let unloaded = vec![false; bes.len()];
+        let engine = Arc::new(Self {
+            db,
+            blob_engines: bes,
+            mad_engine,
+            alloc,
+            init_blob_size,
+            replicas,
+            erasure: fopts.erasure,
+            page_cache: match fopts.page_cache_in_mb {
+                0 => None,
+                mb => Some(PageCache::new(mb << 20, IO_SIZE)),
+            },
+            write_buffer: Arc::new(write_buffer),
+            journal_seq: AtomicU64::new(journal_seq),
+            locks: ChunkLocks::default(),
+            io_depth: fopts.io_depth,
+            io_slots: tokio::sync::Semaphore::new(fopts.io_depth),
+            csum_algo: fopts.csum_algo,
+            compress_algo: fopts.compress_algo,
+            keys,
+            active_key: fopts.active_key,
+            closed: AtomicBool::new(false),
+            shut_down: tokio::sync::Mutex::new(false),
+            unloaded: tokio::sync::Mutex::new(unloaded),
+            large_write: fopts.large_write,
+            class_policy: fopts.class_policy,
+            growing: tokio::sync::Mutex::new(()),
+            fg_ops: AtomicU64::new(0),
+            repairs: PageRepairs::default(),
+            app: Some(opts.app_handle()),
+        });
+        if flusher {
+            Self::start_flusher(&engine);
+        }
+        Self::start_repairer(&engine);
+        Ok(engine)
+    }
+
+    /// create a blob of `init_blob_size` clusters on blobstore `bs`
+    async fn create_blob(be: &BlobEngine, bs: u32, init_blob_size: u64) -> Result<BlobRef> {
+        let blob_id = be.create_blob().await?;
+        let blob = be.open_blob(blob_id).await?;
+        be.resize_blob(blob, init_blob_size).await?;
+        be.sync_blob(blob).await?;
+        be.close_blob(blob).await?;
+        Ok(BlobRef { bs, bid: blob_id })
+    }
+
+    /// make the global free lists match the pages chunk metadata refers to,
+    /// return whether any bit is changed
+    ///
+    /// pages allocated by writes that never committed are freed, pages in
+    /// use but marked free are taken
+    fn reconcile_free_lists(db: &DbEngine, global_meta: &mut MadEngine) -> Result<bool> {
+        let mut used: HashMap<String, BitMap> = global_meta
+            .free_list
+            .iter()
+            .map(|(key, bm)| (key.clone(), BitMap::new(bm.get_size())))
+            .collect();
+        db.scan_meta(b"", b"", |_, v| {
+            let chunk_meta: ChunkMeta = serde_json::from_slice(v)?;
+            for (blob, offset) in chunk_meta.pages().iter().flat_map(|pos| pos.places()) {
+                match used.get_mut(&blob.key()) {
+                    Some(bm) => {
+                        bm.set(offset);
                     }
+                    None => warn!("page on unknown blob {:?}, offset: {}", blob, offset),
+                }
+            }
+            Ok(true)
+        })?;
+        let (mut leaked, mut taken) = (0, 0);
+        for (key, bm) in global_meta.free_list.iter_mut() {
+            let used = &used[key];
+            for i in 0..bm.get_size() {
+                match (bm.get(i), used.get(i)) {
+                    (true, false) => {
+                        bm.clear(i);
+                        leaked += 1;
+                    }
+                    (false, true) => {
+                        bm.set(i);
+                        taken += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if leaked > 0 || taken > 0 {
+            warn!(
+                "free lists disagree with chunk metadata, {} pages freed, {} pages taken",
+                leaked, taken
+            );
+        }
+        Ok(leaked > 0 || taken > 0)
+    }
+
+    /// deal the blobs of each group to allocator workers round-robin and
+    /// reset their free lists from the global ones
+    ///
+    /// blob j of every group, including groups of allocation classes, goes
+    /// to worker j % #workers, so the same metadata and worker count always
+    /// give the same ownership
+    fn assign_thread_blobs(alloc: &AllocPool, global_meta: &MadEngine) {
+        let workers = alloc.states().len();
+        let mut owned: Vec<Vec<(BlobRef, SizeClass)>> = vec![vec![]; workers];
+        for (id, blob) in global_meta.blobs.iter().enumerate() {
+            owned[id % global_meta.blobs_per_group % workers].push((*blob, SizeClass::Small));
+        }
+        for (class, pool) in global_meta.pools.iter() {
+            for group in pool.groups.iter() {
+                for (j, blob) in group.iter().enumerate() {
+                    owned[j % workers].push((*blob, *class));
                 }
-                pool.spawn(async move {
-                    TLS.with(move |f| {
-                        let mut br = f.borrow_mut();
-                        br.tblobs = thread_blobids;
-                        br.tfree_list = blob2map;
-                        br.db = Some(db);
-                    });
-                });
             }
-            pool.join();
-            Ok((
-                Self {
-                    db,
-                    blob_engine: be,
-                    mad_engine,
-                    pool,
-                    init_blob_size,
-                },
-                opts,
-            ))
+        }
+        for (state, thread_blobs) in alloc.states().iter().zip(owned) {
+            let blob2map = thread_blobs
+                .iter()
+                .map(|(blob, _)| (*blob, global_meta.free_list[&blob.key()].clone()))
+                .collect();
+            let mut br = state.lock().unwrap();
+            br.tblobs = thread_blobs.iter().map(|(blob, _)| *blob).collect();
+            br.tfree_list = blob2map;
+            br.tclasses = thread_blobs.into_iter().collect();
         }
     }
 
     /// remove file
     ///
     /// TODO: there should be a backend thread to recycle blob
-    pub fn remove(&self, name: String) -> Result<()> {
-        let global = self
-            .db
-            .get(Hasher::new().checksum(MAGIC.as_bytes()).to_string())?;
-        if global.is_none() {
-            return Err(EngineError::GlobalGetFail);
-        }
-        let mut global_meta: MadEngine =
-            serde_json::from_slice(String::from_utf8(global.unwrap()).unwrap().as_bytes()).unwrap();
-        let chunk_meta = self.db.get(&name).unwrap();
+    pub async fn remove(&self, name: String) -> Result<()> {
+        self.check_open()?;
+        // no read or write is on the pages being freed
+        let _range = self.locks.lock(&name, 0, u64::MAX, true).await?;
+        let _meta = self.locks.meta(&name);
+        let mut l = self.mad_engine.lock().unwrap();
+        let mut global_meta = self.get_global_meta()?;
+        let chunk_meta = self.db.get_meta(&name).unwrap();
         if chunk_meta.is_none() {
             return Ok(());
         }
         let chunk_meta: ChunkMeta =
             serde_json::from_slice(String::from_utf8(chunk_meta.unwrap()).unwrap().as_bytes())
                 .unwrap();
-        let mut old_pages = vec![];
-        if chunk_meta.location.is_some() {
-            let loc = chunk_meta.location.clone().unwrap();
-            for (_, v) in loc {
-                old_pages.push(v.clone());
-            }
-        }
-        let init_blob_size = self.init_blob_size;
-        let db_copy = self.db.clone();
-        let new_blob2map = self
-            .pool
-            .complete(async move {
-                let mut new_blob2map = global_meta.free_list.clone();
-                TLS.with(|f| {
-                    let mut br = f.borrow_mut();
-                    let mut new_blobs = vec![];
-                    for pos in old_pages {
-                        let e = new_blob2map.get_mut(&pos.bid.to_string()).unwrap();
-                        e.clear(pos.offset);
-                        let mut flag = false;
-                        let tblobs = br.tblobs.clone();
-                        for bid in tblobs.iter() {
-                            if *bid == pos.bid {
-                                let bm = br.tfree_list.get_mut(bid);
-                                bm.unwrap().clear(pos.offset);
-                                flag = true;
-                                break;
-                            }
-                        }
-                        if !flag {
-                            for bid in new_blobs.iter() {
-                                if *bid == pos.bid {
-                                    flag = true;
-                                    break;
-                                }
-                            }
-                        }
-                        if !flag {
-                            new_blobs.push(pos.bid);
-                            let mut bm = BitMap::new_set_ones(init_blob_size * CLUSTER_SIZE);
-                            bm.clear(pos.offset);
-                            br.tfree_list.insert(pos.bid, bm);
-                        } else {
-                            let bm = br.tfree_list.get_mut(&pos.bid).unwrap();
-                            bm.clear(pos.offset);
-                        }
-                    }
-                    for new_blob in new_blobs {
-                        br.tblobs.push(new_blob);
-                    }
-                    global_meta.free_list = new_blob2map.clone();
-                    db_copy
-                        .put(
-                            Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
-                            serde_json::to_string(&global_meta.clone())
-                                .unwrap()
-                                .as_bytes(),
-                        )
-                        .unwrap();
-                    new_blob2map
-                })
-            })
-            .await_complete();
-        {
-            let mut l = self.mad_engine.lock().unwrap();
-            l.free_list = new_blob2map;
+        let old_pages = chunk_meta.pages();
+        let sizes = self.recycle_global(&mut global_meta, &old_pages);
+
+        let mut batch = WriteBatch::default();
+        batch.put(
+            Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
+            serde_json::to_string(&global_meta).unwrap().as_bytes(),
+        );
+        self.db.batch_delete_meta(&mut batch, &name);
+        self.db.batch_delete_xattrs(&mut batch, &name)?;
+        self.db.batch_delete_journals(&mut batch, &name)?;
+        self.db.write(batch)?;
+        self.write_buffer.discard(&name);
+        l.free_list = global_meta.free_list;
+        drop(l);
+        self.release_local(old_pages, sizes);
+        Ok(())
+    }
+
+    /// rename file, the metadata is moved in one RocksDB write batch
+    ///
+    /// an existing target is replaced only if `overwrite` is set,
+    /// its pages are freed in the same batch
+    pub async fn rename(&self, old: String, new: String, overwrite: bool) -> Result<()> {
+        self.check_open()?;
+        Self::check_chunk_name(&new)?;
+        // no access is on the pages moved or on those of a replaced target,
+        // both chunks are locked at once
+        let mut names = vec![old.as_str(), new.as_str()];
+        names.dedup();
+        let ranges: Vec<_> = names
+            .iter()
+            .map(|&name| (name, 0, u64::MAX, true))
+            .collect();
+        let _ranges = self.locks.lock_many(&ranges).await?;
+        let _metas = self.locks.metas(&[&old, &new]);
+        let mut l = self.mad_engine.lock().unwrap();
+        let chunk_meta = self.db.get_meta(&old)?;
+        if chunk_meta.is_none() {
+            return Err(EngineError::MetaNotExist);
+        }
+        if old == new {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::default();
+        let mut freed = None;
+        if let Some(target) = self.db.get_meta(&new)? {
+            if !overwrite {
+                return Err(EngineError::MetaExist);
+            }
+            let target: ChunkMeta = serde_json::from_slice(&target)?;
+            let mut global_meta = self.get_global_meta()?;
+            let pages = target.pages();
+            let sizes = self.recycle_global(&mut global_meta, &pages);
+            batch.put(
+                Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
+                serde_json::to_string(&global_meta).unwrap().as_bytes(),
+            );
+            freed = Some((global_meta.free_list, pages, sizes));
+            self.db.batch_delete_xattrs(&mut batch, &new)?;
+            self.db.batch_delete_journals(&mut batch, &new)?;
+        }
+        self.db
+            .batch_put_meta(&mut batch, &new, chunk_meta.unwrap());
+        self.db.batch_delete_meta(&mut batch, &old);
+        self.db.batch_move_xattrs(&mut batch, &old, &new)?;
+        self.db.batch_move_journals(&mut batch, &old, &new)?;
+        self.db.write(batch)?;
+        self.write_buffer.rename(&old, &new);
+        if let Some((free_list, pages, sizes)) = freed {
+            l.free_list = free_list;
+            drop(l);
+            self.release_local(pages, sizes);
         }
-        self.db.delete(name)?;
         Ok(())
     }
 
     /// create file
     pub fn create(&self, name: String) -> Result<()> {
-        let chunk_meta = ChunkMeta::default();
+        self.create_with_checksum(name, self.csum_algo)
+    }
+
+    /// create file whose pages are checksummed with `algo`
+    /// instead of the engine default
+    pub fn create_with_checksum(&self, name: String, algo: ChecksumAlgo) -> Result<()> {
+        self.check_open()?;
+        Self::check_chunk_name(&name)?;
+        let chunk_meta = ChunkMeta {
+            csum_type: algo,
+            key_version: self.active_key,
+            stripe: self.erasure,
+            ..Default::default()
+        };
+        self.db
+            .put_meta(name, serde_json::to_string(&chunk_meta).unwrap().as_bytes())?;
+        Ok(())
+    }
+
+    /// set the expected size of a file, writes to a file expected to reach
+    /// the large write size take pages of the large class
+    pub fn set_size_hint(&self, name: String, size: u64) -> Result<()> {
+        self.check_open()?;
+        let _meta = self.locks.meta(&name);
+        let chunk_meta = match self.db.get_meta(&name)? {
+            Some(chunk_meta) => chunk_meta,
+            None => return Err(EngineError::MetaNotExist),
+        };
+        let mut chunk_meta: ChunkMeta = serde_json::from_slice(&chunk_meta)?;
+        chunk_meta.size_hint = Some(size);
         self.db
-            .put(name, serde_json::to_string(&chunk_meta).unwrap().as_bytes())?;
+            .put_meta(name, serde_json::to_string(&chunk_meta).unwrap().as_bytes())?;
+        Ok(())
+    }
+
+    /// set an extended attribute of a file
+    pub fn set_xattr(&self, name: String, attr: &str, value: &[u8]) -> Result<()> {
+        self.check_open()?;
+        Self::check_xattr_name(attr)?;
+        if self.db.get_meta(&name)?.is_none() {
+            return Err(EngineError::MetaNotExist);
+        }
+        self.db.put_xattr(&name, attr, value)
+    }
+
+    /// get an extended attribute of a file
+    pub fn get_xattr(&self, name: String, attr: &str) -> Result<Vec<u8>> {
+        self.check_open()?;
+        Self::check_xattr_name(attr)?;
+        if self.db.get_meta(&name)?.is_none() {
+            return Err(EngineError::MetaNotExist);
+        }
+        match self.db.get_xattr(&name, attr)? {
+            Some(v) => Ok(v),
+            None => Err(EngineError::XattrNotExist),
+        }
+    }
+
+    /// list extended attribute names of a file
+    pub fn list_xattr(&self, name: String) -> Result<Vec<String>> {
+        self.check_open()?;
+        if self.db.get_meta(&name)?.is_none() {
+            return Err(EngineError::MetaNotExist);
+        }
+        let attrs = self.db.list_xattr(&name)?;
+        Ok(attrs.into_iter().map(|(k, _)| k).collect())
+    }
+
+    /// remove an extended attribute of a file
+    pub fn remove_xattr(&self, name: String, attr: &str) -> Result<()> {
+        self.check_open()?;
+        Self::check_xattr_name(attr)?;
+        if self.db.get_meta(&name)?.is_none() {
+            return Err(EngineError::MetaNotExist);
+        }
+        self.db.delete_xattr(&name, attr)
+    }
+
+    /// attribute names are stored after a `\0` following the chunk name,
+    /// so they cannot hold one
+    fn check_xattr_name(attr: &str) -> Result<()> {
+        if attr.contains('\0') {
+            return Err(EngineError::XattrNameErr(attr.to_string()));
+        }
+        Ok(())
+    }
+
+    /// attribute and journal keys end the chunk name with a `\0`, a name
+    /// holding one could reach the keys of another chunk
+    fn check_chunk_name(name: &str) -> Result<()> {
+        if name.contains('\0') {
+            return Err(EngineError::ChunkNameErr(name.to_string()));
+        }
         Ok(())
     }
 
     /// get a file state
     pub fn stat(&self, name: String) -> Result<StatMeta> {
-        let chunk_meta = self.db.get(name)?;
+        self.check_open()?;
+        let chunk_meta = self.db.get_meta(&name)?;
         if chunk_meta.is_none() {
             return Err(EngineError::MetaNotExist);
         }
         let chunk_meta: ChunkMeta =
             serde_json::from_slice(String::from_utf8(chunk_meta.unwrap()).unwrap().as_bytes())?;
         let ret = StatMeta {
-            size: chunk_meta.size,
+            size: chunk_meta.size.max(self.write_buffer.end(&name)),
             csum_type: chunk_meta.csum_type,
         };
         Ok(ret)
     }
 
-    /// get all new positions to write new data
-    /// recycle old positions
-    fn allocate_and_recycle_poses(
+    /// list chunks whose name starts with `prefix`, in name order
+    ///
+    /// `start_after` is the last name of the previous page, at most `limit`
+    /// chunks are returned
+    pub fn list(
         &self,
-        poses_copy: Vec<PagePos>,
-        mut global_meta: MadEngine,
-        total_page_num: u64,
-    ) -> (Vec<PagePos>, HashMap<String, BitMap>) {
-        let db_copy = self.db.clone();
-        let init_blob_size = self.init_blob_size;
-        let (new_poses, new_blob2map) = self
-            .pool
-            .complete(async move {
-                let mut ret = vec![];
-                let mut new_blob2map = global_meta.free_list.clone();
-                TLS.with(|f| {
-                    let mut br = f.borrow_mut();
-                    let mut cnt = total_page_num;
-                    while cnt > 0 {
-                        let tblobs = br.tblobs.clone();
-                        for bid in tblobs.iter() {
-                            let bm = br.tfree_list.get_mut(bid);
-                            if bm.is_none() {
-                                continue;
-                            }
-                            let bm = bm.unwrap();
-                            let idx = bm.find();
-                            if idx.is_none() {
-                                continue;
-                            }
-                            let idx = idx.unwrap();
-                            // TODO: here should check full or not
-                            ret.push(PagePos {
-                                bid: *bid,
-                                offset: idx,
-                            });
-                            bm.set(idx);
-                            global_meta
-                                .free_list
-                                .insert(bid.clone().to_string(), bm.clone());
-                            cnt -= 1;
-                            if cnt == 0 {
-                                break;
-                            }
-                        }
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, StatMeta)>> {
+        self.check_open()?;
+        let mut ret = vec![];
+        if limit == 0 {
+            return Ok(ret);
+        }
+        let from = match start_after {
+            Some(s) if s > prefix => s,
+            _ => prefix,
+        };
+        self.db
+            .scan_meta(prefix.as_bytes(), from.as_bytes(), |k, v| {
+                if Some(k) == start_after.map(|s| s.as_bytes()) {
+                    return Ok(true);
+                }
+                let chunk_meta: ChunkMeta = serde_json::from_slice(v)?;
+                let name = String::from_utf8_lossy(k).into_owned();
+                let size = chunk_meta.size.max(self.write_buffer.end(&name));
+                ret.push((
+                    name,
+                    StatMeta {
+                        size,
+                        csum_type: chunk_meta.csum_type,
+                    },
+                ));
+                Ok(ret.len() < limit)
+            })?;
+        Ok(ret)
+    }
+
+    /// rebuild pages lost with a replaced device
+    ///
+    /// blobstore `bs` must already be initialized on the new device, every
+    /// blob it held is recreated and lost shards and copies are restored
+    /// from parity or surviving copies, pages without redundancy stay lost.
+    /// Every range of every chunk is locked until it returns, reads and
+    /// writes wait meanwhile.
+    /// Return the number of rewritten chunks.
+    pub async fn rebuild(&self, bs: u32) -> Result<usize> {
+        self.check_open()?;
+        let be = match self.blob_engines.get(bs as usize) {
+            Some(be) => be,
+            None => return Err(EngineError::BsNotExist(bs)),
+        };
+        // nothing allocates or frees pages, or moves chunks, until the
+        // global metadata is replaced
+        let _all = self.locks.lock_all().await?;
+        // recreate blobs, old blob -> new blob
+        let mut global_meta = self.get_global_meta()?;
+        let mut replaced = HashMap::new();
+        let blobs = global_meta.blobs.iter_mut().chain(
+            global_meta
+                .pools
+                .values_mut()
+                .flat_map(|pool| pool.groups.iter_mut().flatten()),
+        );
+        for blob in blobs {
+            let old = *blob;
+            if old.bs != bs {
+                continue;
+            }
+            // blobs of allocation classes may have their own size
+            let blob_size = global_meta
+                .free_list
+                .get(&old.key())
+                .map_or(self.init_blob_size, |bm| bm.get_size() / CLUSTER_SIZE);
+            let new = Self::create_blob(be, bs, blob_size).await?;
+            if let Some(bm) = global_meta.free_list.remove(&old.key()) {
+                global_meta.free_list.insert(new.key(), bm);
+            }
+            *blob = new;
+            replaced.insert(old, new);
+        }
+
+        let mut chunks = vec![];
+        self.db.scan_meta(b"", b"", |k, v| {
+            let chunk_meta: ChunkMeta = serde_json::from_slice(v)?;
+            chunks.push((String::from_utf8_lossy(k).into_owned(), chunk_meta));
+            Ok(true)
+        })?;
+        let mut rebuilt = 0;
+        for (name, mut chunk_meta) in chunks {
+            let hasher = Hasher::with_algo(chunk_meta.csum_type);
+            let cipher = self.chunk_cipher(&chunk_meta)?;
+            let mut changed = false;
+            if let Some(locations) = chunk_meta.location.as_mut() {
+                for (page, pos) in locations.iter_mut() {
+                    if pos.extent.is_some() {
+                        continue;
                     }
-                    // recycle old pages
-                    // TODO: implement a merger
-                    let mut new_blobs = vec![];
-                    for pos in poses_copy {
-                        let e = new_blob2map.get_mut(&pos.bid.to_string()).unwrap();
-                        e.clear(pos.offset);
-                        let mut flag = false;
-                        let tblobs = br.tblobs.clone();
-                        for bid in tblobs.iter() {
-                            if *bid == pos.bid {
-                                let bm = br.tfree_list.get_mut(bid);
-                                bm.unwrap().clear(pos.offset);
-                                flag = true;
-                                break;
-                            }
-                        }
-                        // not in the old tblobs, but maybe in the newblobs
-                        if !flag {
-                            for bid in new_blobs.iter() {
-                                if *bid == pos.bid {
-                                    flag = true;
-                                    break;
-                                }
-                            }
-                        }
-                        if !flag {
-                            new_blobs.push(pos.bid);
-                            let mut bm = BitMap::new_set_ones(init_blob_size * CLUSTER_SIZE);
-                            bm.clear(pos.offset);
-                            br.tfree_list.insert(pos.bid, bm);
-                        } else {
-                            let bm = br.tfree_list.get_mut(&pos.bid).unwrap();
-                            bm.clear(pos.offset);
-                        }
+                    let csum = &mut chunk_meta.csum_data[*page as usize];
+                    match Self::rebuild_page(self, pos, csum, &replaced, &hasher, cipher).await {
+                        Ok(c) => changed |= c,
+                        Err(e) => error!("page {} of {} is lost: {}", page, name, e),
                     }
-                    for new_blob in new_blobs {
-                        br.tblobs.push(new_blob);
+                }
+            }
+            for extent in chunk_meta.extents.values_mut() {
+                for (pos, csum) in extent.poses.iter_mut().zip(extent.csum.iter_mut()) {
+                    match Self::rebuild_page(self, pos, csum, &replaced, &hasher, cipher).await {
+                        Ok(c) => changed |= c,
+                        Err(e) => error!("extent page of {} is lost: {}", name, e),
                     }
-                    global_meta.free_list = new_blob2map.clone();
-                    db_copy
-                        .put(
-                            Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
-                            serde_json::to_string(&global_meta.clone())
-                                .unwrap()
-                                .as_bytes(),
-                        )
-                        .unwrap();
-                });
-                (ret, new_blob2map)
-            })
-            .await_complete();
-        (new_poses, new_blob2map)
-    }
+                }
+            }
+            if let Some(geo) = chunk_meta.stripe {
+                for (index, stripe) in chunk_meta.stripes.iter_mut() {
+                    match Self::rebuild_stripe(self, stripe, geo, &replaced, &hasher, cipher).await
+                    {
+                        Ok(c) => changed |= c,
+                        Err(e) => error!("stripe {} of {} is lost: {}", index, name, e),
+                    }
+                }
+            }
+            if changed {
+                // only pages are rebuilt, other fields may have been updated
+                // meanwhile
+                let _meta = self.locks.meta(&name);
+                if let Some(current) = self.db.get_meta(&name)? {
+                    let mut current: ChunkMeta = serde_json::from_slice(&current)?;
+                    current.location = chunk_meta.location;
+                    current.csum_data = chunk_meta.csum_data;
+                    current.extents = chunk_meta.extents;
+                    current.stripes = chunk_meta.stripes;
+                    self.db
+                        .put_meta(name, serde_json::to_string(&current).unwrap().as_bytes())?;
+                    rebuilt += 1;
+                }
+            }
+        }
 
-    /// partial write a page
-    async fn partial_write(
-        &self,
-        i: usize,
-        pos: &PagePos,
-        buf: &mut DmaBuf,
-        offset: u64,
-        len: u64,
-        start_page: u64,
-        end_page: u64,
-        io_size: u64,
-        data_anchor: &mut usize,
-        data: &[u8],
-        checksum_vec: &mut Vec<u32>,
-        idx_anchor: &mut usize,
-        new_poses: &Vec<PagePos>,
-    ) -> Result<()> {
-        self.blob_engine
-            .read(pos.offset, pos.bid, buf.as_mut())
-            .await
-            .unwrap();
-        let buffer = buf.as_mut();
-        buffer[0..(offset + len - end_page * io_size) as usize]
-            .copy_from_slice(&data[*data_anchor..]);
-        checksum_vec[i + start_page as usize] = Hasher::new().checksum(buffer.as_ref());
-        self.blob_engine
-            .write(
-                new_poses[*idx_anchor].offset,
-                new_poses[*idx_anchor].bid,
-                buffer,
-            )
-            .await
-            .unwrap();
-        *idx_anchor += 1;
-        *data_anchor = data.len();
-        Ok(())
+        self.db.put(
+            Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
+            serde_json::to_string(&global_meta).unwrap().as_bytes(),
+        )?;
+        Self::assign_thread_blobs(&self.alloc, &global_meta);
+        *self.mad_engine.lock().unwrap() = global_meta;
+        Ok(rebuilt)
     }
 
-    /// write file
-    pub async fn write(&self, name: String, offset: u64, data: &[u8]) -> Result<()> {
-        let io_size = IO_SIZE;
-        let len = data.len() as u64;
-
-        // get and check metadata
-        let chunk_meta = self.db.get(&name).unwrap();
-        if chunk_meta.is_none() {
-            return Err(EngineError::MetaNotExist);
+    /// restore places of a page lying on replaced blobs from a surviving copy,
+    /// return whether the page is moved
+    async fn rebuild_page(
+        &self,
+        pos: &mut PagePos,
+        csum: &mut u64,
+        replaced: &HashMap<BlobRef, BlobRef>,
+        hasher: &Hasher,
+        cipher: Option<&Xts>,
+    ) -> Result<bool> {
+        let places = pos.places();
+        if places.iter().all(|(blob, _)| !replaced.contains_key(blob)) {
+            return Ok(false);
         }
-        let mut chunk_meta: ChunkMeta =
-            serde_json::from_slice(String::from_utf8(chunk_meta.unwrap()).unwrap().as_bytes())
-                .unwrap();
-        let size = chunk_meta.get_size();
-        if offset > size {
-            return Err(EngineError::HoleNotAllowed);
+        // read from surviving copies only
+        let mut survivor: Option<PagePos> = None;
+        let mut survivor_csum = 0;
+        for (i, (blob, offset)) in places.iter().enumerate() {
+            if !replaced.contains_key(blob) {
+                survivor = Some(PagePos::from_places(&[(*blob, *offset)]));
+                survivor_csum = if i == 0 {
+                    *csum
+                } else {
+                    pos.copies[i - 1].csum
+                };
+                break;
+            }
         }
+        let survivor = match survivor {
+            Some(s) => s,
+            None => return Err(EngineError::CheckSumErr),
+        };
+        let mut page = vec![0u8; IO_SIZE as usize];
+        Self::read_stored_page(self, &survivor, survivor_csum, hasher, cipher, &mut page).await?;
 
-        let mut checksum_vec = chunk_meta.csum_data.clone();
-        let start_page = offset / io_size;
-        // overwritten pages
-        let cover_end_page = size.min(offset + len - 1) / io_size;
-        // actual end page
-        let end_page = (offset + len - 1) / io_size;
-        // last page before this write, -1 if this is first write
-        let mut last_page = size as i64 / io_size as i64;
+        for (i, (blob, offset)) in places.into_iter().enumerate() {
+            let new = match replaced.get(&blob) {
+                Some(new) => *new,
+                None => continue,
+            };
+            let mut target = PagePos::from_places(&[(new, offset)]);
+            let c = Self::write_stored_page(self, &mut target, hasher, cipher, &page).await?;
+            if i == 0 {
+                pos.bid = new.bid;
+                *csum = c;
+            } else {
+                pos.copies[i - 1].bid = new.bid;
+                pos.copies[i - 1].csum = c;
+            }
+        }
+        Ok(true)
+    }
 
-        // first write
-        if size == 0 {
-            last_page = -1;
+    /// reconstruct shards of a stripe lying on replaced blobs,
+    /// return whether the stripe is moved
+    async fn rebuild_stripe(
+        &self,
+        stripe: &mut Stripe,
+        geo: StripeGeometry,
+        replaced: &HashMap<BlobRef, BlobRef>,
+        hasher: &Hasher,
+        cipher: Option<&Xts>,
+    ) -> Result<bool> {
+        let lost: Vec<usize> = (0..stripe.shards.len())
+            .filter(|i| replaced.contains_key(&stripe.shards[*i].blob()))
+            .collect();
+        if lost.is_empty() {
+            return Ok(false);
+        }
+        let mut shards: Vec<Option<Vec<u8>>> = vec![None; geo.total_shards()];
+        for (i, pos) in stripe.shards.iter().enumerate() {
+            if lost.contains(&i) {
+                continue;
+            }
+            let mut buf = vec![0u8; IO_SIZE as usize];
+            match Self::read_stored_page(self, pos, stripe.csum[i], hasher, cipher, &mut buf).await
+            {
+                Ok(_) => shards[i] = Some(buf),
+                Err(e) => warn!("bad shard {} of stripe: {}", i, e),
+            }
+        }
+        ErasureCode::new(geo)?.reconstruct(&mut shards)?;
+        for i in lost {
+            let pos = &mut stripe.shards[i];
+            pos.bid = replaced[&pos.blob()].bid;
+            stripe.csum[i] =
+                Self::write_stored_page(self, pos, hasher, cipher, shards[i].as_ref().unwrap())
+                    .await?;
         }
-        let total_page_num = end_page - start_page + 1;
+        Ok(true)
+    }
 
-        // global metadata
+    /// get global metadata
+    fn get_global_meta(&self) -> Result<MadEngine> {
         let global = self
             .db
             .get(Hasher::new().checksum(MAGIC.as_bytes()).to_string())?;
@@ -492,283 +904,1691 @@ impl FileEngine {
         }
         let global_meta: MadEngine =
             serde_json::from_slice(String::from_utf8(global.unwrap()).unwrap().as_bytes()).unwrap();
-        let poses = if size == 0 {
-            vec![]
-        } else {
-            (start_page..=cover_end_page)
-                .map(|p| *chunk_meta.location.clone().unwrap().get(&p).unwrap())
-                .collect::<Vec<_>>()
-        };
-
-        // get all new positions to write new data
-        // recycle old positions
-        let poses_copy = poses.clone();
-        let (new_poses, new_blob2map) =
-            Self::allocate_and_recycle_poses(self, poses_copy, global_meta, total_page_num);
+        Ok(global_meta)
+    }
 
-        // *self.mad_engine.lock().unwrap().free_list = new_blob2map;
-        {
-            let mut l = self.mad_engine.lock().unwrap();
-            l.free_list = new_blob2map;
-        }
-        let mut idx_anchor = 0;
-        let mut data_anchor = 0;
-        for (i, pos) in poses.iter().enumerate() {
-            let mut buf = DmaBuf::alloc((io_size) as usize, 0x1000);
-            // read first page
-            if i == 0 {
-                self.blob_engine
-                    .clone()
-                    .read(pos.offset, pos.bid, buf.as_mut())
-                    .await
-                    .unwrap();
-                let buffer = buf.as_mut();
-                buffer[(offset - start_page * io_size) as usize..].copy_from_slice(
-                    &data[data_anchor..(io_size - (offset - start_page * io_size)) as usize],
-                );
-                checksum_vec[i + start_page as usize] = Hasher::new().checksum(buffer.as_ref());
-                self.blob_engine
-                    .clone()
-                    .write(
-                        new_poses[idx_anchor].offset,
-                        new_poses[idx_anchor].bid,
-                        buffer,
-                    )
-                    .await
-                    .unwrap();
-                idx_anchor += 1;
-                data_anchor += (io_size - (offset - start_page * io_size)) as usize;
-            } else if i == poses.len() - 1 {
-                // all over write
-                if end_page as i64 == last_page {
-                    /*
-                        origin last page: |------00000|
-                        write:            |xxxxxxxxx00|
-                    */
-                    if size <= offset + len {
-                        let buffer = buf.as_mut();
-                        buffer.fill(0);
-                        buffer[0..(data.len() - data_anchor)].copy_from_slice(&data[data_anchor..]);
-                        checksum_vec[i + start_page as usize] =
-                            Hasher::new().checksum(buffer.as_ref());
-                        self.blob_engine
-                            .clone()
-                            .write(
-                                new_poses[idx_anchor].offset,
-                                new_poses[idx_anchor].bid,
-                                buffer,
-                            )
-                            .await
-                            .unwrap();
-                        idx_anchor += 1;
-                        data_anchor = data.len();
-                    }
-                    /*
-                        origin last page: |------00000|
-                        write:            |xxxx0000000|
-                    */
-                    else {
-                        Self::partial_write(
-                            self,
-                            i,
-                            pos,
-                            &mut buf,
-                            offset,
-                            len,
-                            start_page,
-                            end_page,
-                            io_size,
-                            &mut data_anchor,
-                            data,
-                            &mut checksum_vec,
-                            &mut idx_anchor,
-                            &new_poses,
-                        )
-                        .await
-                        .unwrap();
-                    }
-                }
-                // partial overwrite, not exceed last page
-                /*
-                    origin: |---|---|---|---|---|
-                    write:     |xxxxxxxxxx|
-                */
-                else if last_page > end_page as i64 {
-                    Self::partial_write(
-                        self,
-                        i,
-                        pos,
-                        &mut buf,
-                        offset,
-                        len,
-                        start_page,
-                        end_page,
-                        io_size,
-                        &mut data_anchor,
-                        data,
-                        &mut checksum_vec,
-                        &mut idx_anchor,
-                        &new_poses,
-                    )
-                    .await
-                    .unwrap();
-                }
-                /*
-                    origin: |---|---|---|---|---|
-                    write:     |xxxxxxxxxxxxxxxxxxx|
-                */
-                else {
-                    self.blob_engine
-                        .write(
-                            new_poses[idx_anchor].offset,
-                            new_poses[idx_anchor].bid,
-                            &data[data_anchor..(data_anchor + io_size as usize)],
-                        )
-                        .await
-                        .unwrap();
-                    checksum_vec[i + start_page as usize] = Hasher::new()
-                        .checksum(&data[data_anchor..(data_anchor + io_size as usize)]);
-                    idx_anchor += 1;
-                    data_anchor += io_size as usize;
-                }
-            } else {
-                self.blob_engine
-                    .write(
-                        new_poses[idx_anchor].offset,
-                        new_poses[idx_anchor].bid,
-                        &data[data_anchor..(data_anchor + io_size as usize)],
-                    )
-                    .await
-                    .unwrap();
-                checksum_vec[i + start_page as usize] =
-                    Hasher::new().checksum(&data[data_anchor..(data_anchor + io_size as usize)]);
-                idx_anchor += 1;
-                data_anchor += io_size as usize;
-            }
-        }
-        if end_page as i64 > last_page {
-            for page in last_page + 1..=end_page as i64 {
-                let mut buf = DmaBuf::alloc((io_size) as usize, 0x1000);
-                buf.as_mut().fill(0);
-                if page as u64 == end_page {
-                    let buffer = buf.as_mut();
-                    buffer[0..(data.len() - data_anchor)].copy_from_slice(&data[data_anchor..]);
-                    checksum_vec.push(Hasher::new().checksum(buffer.as_ref()));
-                    self.blob_engine
-                        .write(
-                            new_poses[idx_anchor].offset,
-                            new_poses[idx_anchor].bid,
-                            buffer,
-                        )
-                        .await
-                        .unwrap();
-                    idx_anchor += 1;
-                    data_anchor = data.len();
-                } else {
-                    buf.as_mut()
-                        .copy_from_slice(&data[data_anchor..(data_anchor + io_size as usize)]);
-                    self.blob_engine
-                        .write(
-                            new_poses[idx_anchor].offset,
-                            new_poses[idx_anchor].bid,
-                            buf.as_ref(),
-                        )
-                        .await
-                        .unwrap();
-                    checksum_vec.push(
-                        Hasher::new()
-                            .checksum(&data[data_anchor..(data_anchor + io_size as usize)]),
-                    );
-                    idx_anchor += 1;
-                    data_anchor += io_size as usize;
+    /// clear old positions and all their copies in the global free list
+    ///
+    /// `global_meta` is not persisted, caller should put it together with
+    /// the chunk metadata change and then hand the positions to
+    /// `release_local`; the size of every blob touched is returned for it
+    fn recycle_global(
+        &self,
+        global_meta: &mut MadEngine,
+        old_pages: &[PagePos],
+    ) -> HashMap<BlobRef, (u64, SizeClass)> {
+        // a freed position may be taken by another page before its repair
+        self.repairs
+            .cancel(old_pages.iter().flat_map(|pos| pos.places()));
+        let mut sizes = HashMap::new();
+        for (blob, offset) in old_pages.iter().flat_map(|pos| pos.places()) {
+            let size = match global_meta.free_list.get_mut(&blob.key()) {
+                Some(bm) => {
+                    bm.clear(offset);
+                    bm.get_size()
                 }
-            }
-        }
-        chunk_meta.size = size.max(offset + data.len() as u64);
-        let mut locations = match chunk_meta.location {
-            Some(l) => l,
-            None => HashMap::new(),
-        };
-        let mut idx = 0;
-        for page in start_page..=end_page {
-            let pos = PagePos {
-                bid: new_poses[idx].bid,
-                offset: new_poses[idx].offset,
+                None => self.init_blob_size * CLUSTER_SIZE,
             };
-            locations.insert(page, pos);
-            idx += 1;
+            sizes.insert(blob, (size, global_meta.blob_class(&blob)));
         }
-        chunk_meta.location = Some(locations);
-        chunk_meta.csum_data = checksum_vec;
-        chunk_meta.csum_type = "CRC32".into();
-        self.db
-            .put(name, serde_json::to_string(&chunk_meta).unwrap().as_bytes())?;
-        Ok(())
+        sizes
     }
 
-    /// read a chunk, this should return the read length
+    /// give old positions back to the free list of an allocator worker
     ///
-    /// TODO: check read range, return read length
-    pub async fn read(&self, name: String, offset: u64, data: &mut [u8]) -> Result<()> {
-        let len = data.len() as u64;
-        let io_size = IO_SIZE;
-        let start_page = offset / io_size;
-        let end_page = (offset + len - 1) / io_size;
+    /// called only once the change freeing them is committed, so a failed
+    /// write batch never leaves them allocatable
+    fn release_local(&self, old_pages: Vec<PagePos>, sizes: HashMap<BlobRef, (u64, SizeClass)>) {
+        self.invalidate_pages(&old_pages);
+        self.alloc
+            .run(move |br| Self::recycle_local(br, old_pages, &sizes));
+    }
 
-        let chunk_meta = self.db.get(name)?;
-        if chunk_meta.is_none() {
-            return Err(EngineError::MetaNotExist);
-        }
-        let chunk_meta: ChunkMeta =
-            serde_json::from_slice(String::from_utf8(chunk_meta.unwrap()).unwrap().as_bytes())?;
-        let size = chunk_meta.size;
-        if offset >= size || offset + len > size {
-            return Err(EngineError::ReadOutRange);
+    /// drop freed pages from page cache
+    fn invalidate_pages(&self, pages: &[PagePos]) {
+        if let Some(cache) = &self.page_cache {
+            for pos in pages {
+                cache.invalidate(&(pos.blob(), pos.offset));
+            }
         }
+    }
 
-        let poses = (start_page..=end_page)
-            .map(|p| *chunk_meta.location.clone().unwrap().get(&p).unwrap())
-            .collect::<Vec<_>>();
-        let checksum_vec = chunk_meta.csum_data.clone();
-        let mut buf = DmaBuf::alloc((io_size) as usize, 0x1000);
-        let mut anchor = 0;
-        for (i, pos) in poses.iter().enumerate() {
-            self.blob_engine
-                .clone()
-                .read(pos.offset, pos.bid, buf.as_mut())
-                .await?;
-            if Hasher::new().checksum(buf.as_ref()) != checksum_vec[i + start_page as usize] {
-                return Err(EngineError::CheckSumErr);
-            }
-            // last page
-            if i == poses.len() - 1 {
-                let end = offset + len - 1 - end_page * io_size;
-                let buffer = buf.as_ref();
-                data[anchor..].copy_from_slice(&buffer[0..=end as usize]);
-            }
-            // first page
-            else if i == 0 {
-                let buffer = buf.as_ref();
-                data[anchor..((start_page + 1) * io_size - offset) as usize]
-                    .copy_from_slice(&buffer[((offset - start_page * io_size) as usize)..]);
-                anchor += ((start_page + 1) * io_size - offset) as usize;
-            } else {
-                data[anchor..(anchor + io_size as usize)].copy_from_slice(buf.as_ref());
-                anchor += io_size as usize;
+    /// clear old positions and all their copies in the worker free list,
+    /// blobs owned by other workers are taken over with the size and class
+    /// in `sizes`
+    ///
+    /// TODO: implement a merger
+    fn recycle_local(
+        br: &mut ThreadData,
+        old_pages: Vec<PagePos>,
+        sizes: &HashMap<BlobRef, (u64, SizeClass)>,
+    ) {
+        for (blob, offset) in old_pages.iter().flat_map(|pos| pos.places()) {
+            match br.tfree_list.get_mut(&blob) {
+                Some(bm) => {
+                    bm.clear(offset);
+                }
+                None => {
+                    let (size, class) = sizes[&blob];
+                    let mut bm = BitMap::new_set_ones(size);
+                    bm.clear(offset);
+                    br.tfree_list.insert(blob, bm);
+                    br.tblobs.push(blob);
+                    br.tclasses.insert(blob, class);
+                }
             }
         }
-        Ok(())
     }
 
-    /// unload blobstore
-    pub async fn unload_bs(&self) -> Result<()> {
-        self.blob_engine.unload().await;
-        Ok(())
+    /// allocate `groups` groups of `group_size` places on blobs of `class`,
+    /// the class grows first if an allocator worker runs short of it
+    ///
+    /// pages of other classes are taken only once the class can not grow
+    /// and has no space left
+    async fn allocate_class(
+        &self,
+        groups: u64,
+        group_size: usize,
+        class: SizeClass,
+    ) -> Result<Vec<Vec<(BlobRef, u64)>>> {
+        let pages = groups * group_size as u64;
+        self.grow_class(class, pages).await?;
+        match self.allocate_poses(groups, group_size, Some(class)) {
+            Err(EngineError::NoSpace) => {
+                warn!(
+                    "{:?} class has no space for {} pages, take pages of other classes",
+                    class, pages
+                );
+                self.allocate_poses(groups, group_size, None)
+            }
+            res => res,
+        }
     }
 
-    /// close thread pool
-    pub fn close_engine(&mut self) -> Result<()> {
-        self.pool.to_owned().shutdown();
+    /// allocate `groups` groups of `group_size` places, on blobs of `class`
+    /// or of any class if it is none
+    ///
+    /// places are taken from an allocator worker first, the global metadata
+    /// lock is only held to mark them used in the global free list
+    fn allocate_poses(
+        &self,
+        groups: u64,
+        group_size: usize,
+        class: Option<SizeClass>,
+    ) -> Result<Vec<Vec<(BlobRef, u64)>>> {
+        let num_bs = self.blob_engines.len() as u32;
+        let places = self
+            .alloc
+            .run(move |br| Self::allocate_local(br, groups, group_size, class, num_bs))?;
+
+        let mut l = self.mad_engine.lock().unwrap();
+        let ret = self.get_global_meta().and_then(|mut global_meta| {
+            for (blob, idx) in places.iter().flatten() {
+                if let Some(bm) = global_meta.free_list.get_mut(&blob.key()) {
+                    bm.set(*idx);
+                }
+            }
+            self.db.put(
+                Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
+                serde_json::to_string(&global_meta).unwrap().as_bytes(),
+            )?;
+            Ok(global_meta.free_list)
+        });
+        match ret {
+            Ok(free_list) => {
+                l.free_list = free_list;
+                Ok(places)
+            }
+            Err(e) => {
+                // the places are still free in the global free list
+                let pages: Vec<PagePos> = places.iter().map(|p| PagePos::from_places(p)).collect();
+                let mut sizes = HashMap::new();
+                for (blob, _) in places.iter().flatten() {
+                    let size = l
+                        .free_list
+                        .get(&blob.key())
+                        .map_or(self.init_blob_size * CLUSTER_SIZE, |bm| bm.get_size());
+                    sizes.insert(*blob, (size, l.blob_class(blob)));
+                }
+                drop(l);
+                self.release_local(pages, sizes);
+                Err(e)
+            }
+        }
+    }
+
+    /// take all new positions to write new data from the worker free list
+    ///
+    /// `groups` groups of `group_size` places on distinct blobs of `class`,
+    /// or of any class if it is none, are returned, place i of a group
+    /// prefers blobstore i % #blobstores
+    fn allocate_local(
+        br: &mut ThreadData,
+        groups: u64,
+        group_size: usize,
+        class: Option<SizeClass>,
+        num_bs: u32,
+    ) -> Result<Vec<Vec<(BlobRef, u64)>>> {
+        let mut ret: Vec<Vec<(BlobRef, u64)>> = vec![];
+        // a big write takes one contiguous run per place if it can
+        if let Some(runs) = Self::allocate_runs(br, groups, group_size, class, num_bs) {
+            ret = (0..groups)
+                .map(|g| {
+                    runs.iter()
+                        .map(|(blob, start)| (*blob, start + g))
+                        .collect()
+                })
+                .collect();
+        }
+        for _ in ret.len() as u64..groups {
+            let mut places: Vec<(BlobRef, u64)> = vec![];
+            for i in 0..group_size {
+                let candidates = Self::candidates(br, class, i as u32 % num_bs, &places);
+                let found = candidates.into_iter().find_map(|blob| {
+                    let bm = br.tfree_list.get_mut(&blob)?;
+                    let idx = bm.find()?;
+                    bm.set(idx);
+                    Some((blob, idx))
+                });
+                match found {
+                    Some(place) => places.push(place),
+                    None => {
+                        // give back every bit set so far
+                        for (blob, idx) in ret.into_iter().flatten().chain(places) {
+                            br.tfree_list.get_mut(&blob).unwrap().clear(idx);
+                        }
+                        return Err(EngineError::NoSpace);
+                    }
+                }
+            }
+            ret.push(places);
+        }
+        Ok(ret)
+    }
+
+    /// take a run of `groups` free pages for each of the `group_size` places,
+    /// on distinct blobs, none if some place has no such run
+    fn allocate_runs(
+        br: &mut ThreadData,
+        groups: u64,
+        group_size: usize,
+        class: Option<SizeClass>,
+        num_bs: u32,
+    ) -> Option<Vec<(BlobRef, u64)>> {
+        if groups < 2 {
+            return None;
+        }
+        let mut runs: Vec<(BlobRef, u64)> = vec![];
+        for i in 0..group_size {
+            let candidates = Self::candidates(br, class, i as u32 % num_bs, &runs);
+            let found = candidates.into_iter().find_map(|blob| {
+                let bm = br.tfree_list.get_mut(&blob)?;
+                let start = bm.find_range(groups)?;
+                bm.set_range(start, groups);
+                Some((blob, start))
+            });
+            match found {
+                Some(run) => runs.push(run),
+                None => {
+                    for (blob, start) in runs {
+                        br.tfree_list
+                            .get_mut(&blob)
+                            .unwrap()
+                            .clear_range(start, groups);
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(runs)
+    }
+
+    /// worker blobs of `class`, or of any class if it is none, that are not
+    /// `taken` yet, blobs on blobstore `prefer` come first
+    fn candidates(
+        br: &ThreadData,
+        class: Option<SizeClass>,
+        prefer: u32,
+        taken: &[(BlobRef, u64)],
+    ) -> Vec<BlobRef> {
+        let mut candidates: Vec<BlobRef> = br
+            .tblobs
+            .iter()
+            .copied()
+            .filter(|b| class.map_or(true, |c| br.tclasses.get(b) == Some(&c)))
+            .filter(|b| taken.iter().all(|(t, _)| t != b))
+            .collect();
+        candidates.sort_by_key(|b| b.bs != prefer);
+        candidates
+    }
+
+    /// allocation class of a write of `len` bytes to a chunk
+    fn size_class(&self, chunk_meta: &ChunkMeta, len: u64) -> SizeClass {
+        let hint = chunk_meta.size_hint.unwrap_or(0);
+        if self.large_write > 0 && len.max(hint) >= self.large_write {
+            SizeClass::Large
+        } else {
+            SizeClass::Small
+        }
+    }
+
+    /// add blob groups to `class` if the blobs of the class some allocator
+    /// worker owns have fewer than `pages` free pages, and its growth policy
+    /// allows
+    ///
+    /// an allocation runs on any worker, so every worker needs the space
+    ///
+    /// one group is added for each group created on open, group g of the
+    /// class is put on blobstore g % #blobstores, so copies and shards still
+    /// spread over blobstores
+    async fn grow_class(&self, class: SizeClass, pages: u64) -> Result<()> {
+        let policy = match self.class_policy.get(&class) {
+            Some(policy) if policy.max_groups > 0 => *policy,
+            _ => return Ok(()),
+        };
+        let _growing = self.growing.lock().await;
+        let short = self
+            .alloc
+            .states()
+            .iter()
+            .any(|state| state.lock().unwrap().class_free(class) < pages);
+        if !short {
+            return Ok(());
+        }
+        let (groups, step) = {
+            let l = self.mad_engine.lock().unwrap();
+            (
+                l.pools.get(&class).map_or(0, |pool| pool.groups.len()),
+                l.blobs.len() / l.blobs_per_group,
+            )
+        };
+        if groups + step > policy.max_groups {
+            warn!(
+                "{:?} class is full at {} groups, take pages of other classes",
+                class, groups
+            );
+            return Ok(());
+        }
+        let workers = self.alloc.states().len();
+        let mut new_groups = vec![];
+        for g in groups..groups + step {
+            let bs = (g % self.blob_engines.len()) as u32;
+            let mut group = vec![];
+            for _ in 0..workers {
+                let be = &self.blob_engines[bs as usize];
+                group.push(Self::create_blob(be, bs, policy.blob_size).await?);
+            }
+            new_groups.push(group);
+        }
+
+        let mut l = self.mad_engine.lock().unwrap();
+        let mut global_meta = self.get_global_meta()?;
+        let bitmap = BitMap::new(policy.blob_size * CLUSTER_SIZE);
+        for blob in new_groups.iter().flatten() {
+            global_meta.free_list.insert(blob.key(), bitmap.clone());
+        }
+        global_meta
+            .pools
+            .entry(class)
+            .or_default()
+            .groups
+            .extend(new_groups.iter().cloned());
+        self.db.put(
+            Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
+            serde_json::to_string(&global_meta).unwrap().as_bytes(),
+        )?;
+        for group in new_groups {
+            for (j, blob) in group.into_iter().enumerate() {
+                let mut br = self.alloc.states()[j % workers].lock().unwrap();
+                br.tblobs.push(blob);
+                br.tfree_list.insert(blob, bitmap.clone());
+                br.tclasses.insert(blob, class);
+            }
+        }
+        info!("{:?} class grows to {} groups", class, groups + step);
+        *l = global_meta;
+        Ok(())
+    }
+
+    /// run the page I/Os of one request concurrently, results are in
+    /// submission order
+    ///
+    /// the I/Os they issue, however deeply nested, share the `io_depth`
+    /// limit of the engine
+    async fn fan_out<T, F>(&self, ios: impl IntoIterator<Item = F>) -> Result<Vec<T>>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        stream::iter(ios)
+            .buffered(self.io_depth)
+            .try_collect()
+            .await
+    }
+
+    /// submit page I/Os in batches per blobstore, batches complete
+    /// independently and at most `io_depth` page I/Os of the engine are in
+    /// flight
+    ///
+    /// each I/O is handed back with its status in submission order
+    async fn submit(&self, ios: Vec<(u32, BlobIo)>) -> Result<Vec<(BlobIo, Result<()>)>> {
+        // a batch takes a part of the depth, so batches overlap
+        let batch_len = (self.io_depth / 4).max(1);
+        let mut results: Vec<Option<(BlobIo, Result<()>)>> = ios.iter().map(|_| None).collect();
+        let mut groups: HashMap<u32, (Vec<usize>, Vec<BlobIo>)> = HashMap::new();
+        let mut batches = vec![];
+        for (i, (bs, io)) in ios.into_iter().enumerate() {
+            let group = groups.entry(bs).or_default();
+            group.0.push(i);
+            group.1.push(io);
+            if group.1.len() == batch_len {
+                batches.push((bs, groups.remove(&bs).unwrap()));
+            }
+        }
+        batches.extend(groups);
+        let mut done = stream::iter(batches)
+            .map(|(bs, (idx, ios))| async move {
+                let _slots = self.io_slot(ios.len()).await?;
+                let be = &self.blob_engines[bs as usize];
+                be.batch(ios).await.map(|rets| (idx, rets))
+            })
+            .buffer_unordered(self.io_depth);
+        while let Some(ret) = done.next().await {
+            let (idx, rets) = ret?;
+            for (i, ret) in idx.into_iter().zip(rets) {
+                results[i] = Some(ret);
+            }
+        }
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// wait until `n` more page I/Os may be in flight, they are counted
+    /// until the permit is dropped
+    async fn io_slot(&self, n: usize) -> Result<tokio::sync::SemaphorePermit<'_>> {
+        self.io_slots
+            .acquire_many(n as u32)
+            .await
+            .map_err(|_| EngineError::Closed)
+    }
+
+    /// image of a page as stored at a place, encrypted with its tweak
+    fn stored_image(blob: BlobRef, offset: u64, page: &[u8], cipher: Option<&Xts>) -> DmaBuf {
+        let mut buf = DmaBuf::alloc(page.len(), 0x1000);
+        buf.as_mut().copy_from_slice(page);
+        if let Some(cipher) = cipher {
+            cipher.encrypt(page_tweak(blob, offset), buf.as_mut());
+        }
+        buf
+    }
+
+    /// write page images to their primary positions and every copy in
+    /// batches, return each position with the checksum of its primary copy
+    ///
+    /// see `write_stored_page`
+    async fn write_stored_pages(
+        &self,
+        pages: Vec<(PagePos, &[u8])>,
+        hasher: &Hasher,
+        cipher: Option<&Xts>,
+    ) -> Result<Vec<(PagePos, u64)>> {
+        // (place, stored image) of every copy
+        let mut images = vec![];
+        let mut written = vec![];
+        for (mut pos, page) in pages {
+            for copy in pos.copies.iter_mut() {
+                let blob = BlobRef {
+                    bs: copy.bs,
+                    bid: copy.bid,
+                };
+                let buf = Self::stored_image(blob, copy.offset, page, cipher);
+                copy.csum = hasher.checksum(buf.as_ref());
+                images.push((blob, copy.offset, buf));
+            }
+            let buf = Self::stored_image(pos.blob(), pos.offset, page, cipher);
+            let csum = hasher.checksum(buf.as_ref());
+            images.push((pos.blob(), pos.offset, buf));
+            written.push((pos, csum));
+        }
+        let ios = images
+            .into_iter()
+            .map(|(blob, offset, buf)| (blob.bs, BlobIo::Write(blob.bid, offset, buf)))
+            .collect();
+        for (_, ret) in self.submit(ios).await? {
+            ret?;
+        }
+        if let Some(cache) = &self.page_cache {
+            for (pos, _) in written.iter() {
+                cache.invalidate(&(pos.blob(), pos.offset));
+            }
+        }
+        Ok(written)
+    }
+
+    /// read stored pages, primary copies are read in batches and verified
+    /// afterwards, a page whose primary copy is bad goes through
+    /// `read_stored_page`, which tries its other copies
+    async fn read_stored_pages(
+        &self,
+        pages: &[(&PagePos, u64)],
+        hasher: &Hasher,
+        cipher: Option<&Xts>,
+    ) -> Result<Vec<Result<Vec<u8>>>> {
+        let io_size = IO_SIZE as usize;
+        let mut out: Vec<Option<Result<Vec<u8>>>> = pages.iter().map(|_| None).collect();
+        let mut misses = vec![];
+        for (i, (pos, _)) in pages.iter().enumerate() {
+            let mut page = vec![0u8; io_size];
+            match &self.page_cache {
+                Some(cache) if cache.get(&(pos.blob(), pos.offset), &mut page) => {
+                    out[i] = Some(Ok(page))
+                }
+                _ => misses.push((i, DmaBuf::alloc(io_size, 0x1000))),
+            }
+        }
+        let (idx, ios): (Vec<usize>, Vec<(u32, BlobIo)>) = misses
+            .into_iter()
+            .map(|(i, buf)| {
+                let pos = pages[i].0;
+                (i, (pos.bs, BlobIo::Read(pos.bid, pos.offset, buf)))
+            })
+            .unzip();
+        let rets = self.submit(ios).await?;
+        let mut retry = vec![];
+        for (i, (io, ret)) in idx.into_iter().zip(rets) {
+            let (pos, csum) = pages[i];
+            let mut buf = io.into_buf();
+            match ret {
+                Ok(_) if hasher.checksum(buf.as_ref()) == csum => {
+                    if let Some(cipher) = cipher {
+                        cipher.decrypt(page_tweak(pos.blob(), pos.offset), buf.as_mut());
+                    }
+                    if let Some(cache) = &self.page_cache {
+                        cache.insert((pos.blob(), pos.offset), buf.as_ref());
+                    }
+                    out[i] = Some(Ok(buf.as_ref().to_vec()));
+                }
+                _ => retry.push(i),
+            }
+        }
+        for i in retry {
+            let (pos, csum) = pages[i];
+            let mut page = vec![0u8; io_size];
+            let ret = Self::read_stored_page(self, pos, csum, hasher, cipher, &mut page).await;
+            out[i] = Some(ret.map(|_| page));
+        }
+        Ok(out.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// write one page image to its primary position and every copy
+    ///
+    /// each copy is encrypted with the tweak of its own position, checksums
+    /// of copies are kept in `pos`, return checksum of the primary copy
+    async fn write_stored_page(
+        &self,
+        pos: &mut PagePos,
+        hasher: &Hasher,
+        cipher: Option<&Xts>,
+        page: &[u8],
+    ) -> Result<u64> {
+        let mut buf = DmaBuf::alloc(page.len(), 0x1000);
+        for copy in pos.copies.iter_mut() {
+            let blob = BlobRef {
+                bs: copy.bs,
+                bid: copy.bid,
+            };
+            buf.as_mut().copy_from_slice(page);
+            if let Some(cipher) = cipher {
+                cipher.encrypt(page_tweak(blob, copy.offset), buf.as_mut());
+            }
+            let slot = self.io_slot(1).await?;
+            buf = self.blob_engines[copy.bs as usize]
+                .write(copy.offset, copy.bid, buf)
+                .await?;
+            drop(slot);
+            copy.csum = hasher.checksum(buf.as_ref());
+        }
+        buf.as_mut().copy_from_slice(page);
+        if let Some(cipher) = cipher {
+            cipher.encrypt(page_tweak(pos.blob(), pos.offset), buf.as_mut());
+        }
+        let slot = self.io_slot(1).await?;
+        let buf = self.blob_engines[pos.bs as usize]
+            .write(pos.offset, pos.bid, buf)
+            .await?;
+        drop(slot);
+        if let Some(cache) = &self.page_cache {
+            cache.invalidate(&(pos.blob(), pos.offset));
+        }
+        Ok(hasher.checksum(buf.as_ref()))
+    }
+
+    /// read one stored page into `out`, trying the primary copy first
+    ///
+    /// copies that cannot be read or fail the checksum are queued for the
+    /// repairer with the data of the first good one, the caller holds a
+    /// range lock on the page so its places are not recycled before that
+    async fn read_stored_page(
+        &self,
+        pos: &PagePos,
+        csum: u64,
+        hasher: &Hasher,
+        cipher: Option<&Xts>,
+        out: &mut [u8],
+    ) -> Result<()> {
+        if let Some(cache) = &self.page_cache {
+            if cache.get(&(pos.blob(), pos.offset), out) {
+                return Ok(());
+            }
+        }
+        let mut csums = vec![csum];
+        csums.extend(pos.copies.iter().map(|c| c.csum));
+        let places = pos.places();
+        let mut bad = vec![];
+        let mut err = EngineError::CheckSumErr;
+        let mut good = None;
+        for (i, (blob, offset)) in places.iter().enumerate() {
+            let buf = DmaBuf::alloc(out.len(), 0x1000);
+            let slot = self.io_slot(1).await?;
+            let ret = self.blob_engines[blob.bs as usize]
+                .read(*offset, blob.bid, buf)
+                .await;
+            drop(slot);
+            match ret {
+                Ok(buf) if hasher.checksum(buf.as_ref()) == csums[i] => {
+                    good = Some((i, buf));
+                    break;
+                }
+                Ok(_) => err = EngineError::CheckSumErr,
+                Err(e) => err = e,
+            }
+            warn!("bad copy of page, blob: {:?}, offset: {}", blob, offset);
+            bad.push(i);
+        }
+        let (good, mut buf) = match good {
+            Some(good) => good,
+            None => return Err(err),
+        };
+        let (blob, offset) = places[good];
+        if let Some(cipher) = cipher {
+            cipher.decrypt(page_tweak(blob, offset), buf.as_mut());
+        }
+        out.copy_from_slice(buf.as_ref());
+        if let Some(cache) = &self.page_cache {
+            cache.insert((pos.blob(), pos.offset), out);
+        }
+
+        // bad copies are rewritten later by the repairer, the bytes queued
+        // match their recorded checksum again
+        for i in bad {
+            let (blob, offset) = places[i];
+            let mut data = out.to_vec();
+            if let Some(cipher) = cipher {
+                cipher.encrypt(page_tweak(blob, offset), &mut data);
+            }
+            self.repairs.queue(blob, offset, data);
+        }
+        Ok(())
+    }
+
+    /// write the page copies queued by reads
+    ///
+    /// every range is locked, so no queued position is freed and taken by
+    /// another page while it is written; repairs are rare, and reads and
+    /// writes wait only while some are written
+    async fn repair_pages(&self) -> Result<()> {
+        if self.repairs.is_empty() {
+            return Ok(());
+        }
+        let _all = self.locks.lock_all().await?;
+        for ((blob, offset), data) in self.repairs.take() {
+            let mut buf = DmaBuf::alloc(data.len(), 0x1000);
+            buf.as_mut().copy_from_slice(&data);
+            let slot = self.io_slot(1).await?;
+            let ret = self.blob_engines[blob.bs as usize]
+                .write(offset, blob.bid, buf)
+                .await;
+            drop(slot);
+            match ret {
+                Ok(_) => info!("repair page, blob: {:?}, offset: {}", blob, offset),
+                Err(e) => error!("fail to repair page, blob: {:?}, error: {}", blob, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// read one logical page of a chunk into `out` and verify its checksum
+    ///
+    /// pages of a compressed extent are served from `extent_cache`, an
+    /// extent missing there is read and decompressed
+    async fn read_page(
+        &self,
+        chunk_meta: &ChunkMeta,
+        hasher: &Hasher,
+        page: u64,
+        extent_cache: &HashMap<u64, Vec<u8>>,
+        out: &mut [u8],
+    ) -> Result<()> {
+        let io_size = IO_SIZE;
+        if let Some(geo) = chunk_meta.stripe {
+            return Self::read_striped_page(self, chunk_meta, geo, hasher, page, out).await;
+        }
+        let pos = match chunk_meta.location.as_ref().and_then(|l| l.get(&page)) {
+            Some(pos) => pos,
+            None => return Err(EngineError::ReadOutRange),
+        };
+        match pos.extent {
+            None => {
+                let cipher = self.chunk_cipher(chunk_meta)?;
+                let csum = chunk_meta.csum_data[page as usize];
+                Self::read_stored_page(self, pos, csum, hasher, cipher, out).await?;
+            }
+            Some(id) => {
+                let extent = match chunk_meta.extents.get(&id) {
+                    Some(extent) => extent,
+                    None => return Err(EngineError::MetaNotExist),
+                };
+                let start = ((page - extent.first_page) * io_size) as usize;
+                match extent_cache.get(&id) {
+                    Some(raw) => out.copy_from_slice(&raw[start..start + io_size as usize]),
+                    None => {
+                        let cipher = self.chunk_cipher(chunk_meta)?;
+                        let raw = Self::read_extent(self, extent, hasher, cipher).await?;
+                        out.copy_from_slice(&raw[start..start + io_size as usize]);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// read logical pages of a chunk concurrently and verify their checksums
+    ///
+    /// compressed extents holding any of the pages are read first, once each
+    async fn read_pages(
+        &self,
+        chunk_meta: &ChunkMeta,
+        hasher: &Hasher,
+        pages: &[u64],
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut ids: Vec<u64> = pages
+            .iter()
+            .filter_map(|page| chunk_meta.location.as_ref()?.get(page)?.extent)
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut extent_cache = HashMap::new();
+        if !ids.is_empty() {
+            let cipher = self.chunk_cipher(chunk_meta)?;
+            let raws = self
+                .fan_out(ids.iter().map(|id| async move {
+                    match chunk_meta.extents.get(id) {
+                        Some(extent) => Self::read_extent(self, extent, hasher, cipher).await,
+                        None => Err(EngineError::MetaNotExist),
+                    }
+                }))
+                .await?;
+            extent_cache.extend(ids.into_iter().zip(raws));
+        }
+
+        // pages stored uncompressed are read in batches, the others are
+        // copied from extents, bad ones are retried one by one, which
+        // reconstructs stripes
+        let cipher = self.chunk_cipher(chunk_meta)?;
+        let (idx, places): (Vec<usize>, Vec<(&PagePos, u64)>) = pages
+            .iter()
+            .enumerate()
+            .filter_map(|(i, page)| Some((i, Self::raw_page(chunk_meta, *page)?)))
+            .unzip();
+        let mut out: Vec<Option<Vec<u8>>> = pages.iter().map(|_| None).collect();
+        let rets = self.read_stored_pages(&places, hasher, cipher).await?;
+        for (i, ret) in idx.into_iter().zip(rets) {
+            out[i] = ret.ok();
+        }
+        for (i, page) in pages.iter().enumerate() {
+            if out[i].is_none() {
+                let mut buf = vec![0u8; IO_SIZE as usize];
+                Self::read_page(self, chunk_meta, hasher, *page, &extent_cache, &mut buf).await?;
+                out[i] = Some(buf);
+            }
+        }
+        Ok(out.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// stored position and checksum of a logical page kept uncompressed
+    fn raw_page(chunk_meta: &ChunkMeta, page: u64) -> Option<(&PagePos, u64)> {
+        match chunk_meta.stripe {
+            Some(geo) => {
+                let k = geo.data_shards as u64;
+                let stripe = chunk_meta.stripes.get(&(page / k))?;
+                let idx = (page % k) as usize;
+                Some((&stripe.shards[idx], stripe.csum[idx]))
+            }
+            None => {
+                let pos = chunk_meta.location.as_ref()?.get(&page)?;
+                match pos.extent {
+                    Some(_) => None,
+                    None => Some((pos, *chunk_meta.csum_data.get(page as usize)?)),
+                }
+            }
+        }
+    }
+
+    /// read a compressed extent, checksums cover the stored bytes
+    async fn read_extent(
+        &self,
+        extent: &Extent,
+        hasher: &Hasher,
+        cipher: Option<&Xts>,
+    ) -> Result<Vec<u8>> {
+        let io_size = IO_SIZE;
+        let places: Vec<(&PagePos, u64)> = extent
+            .poses
+            .iter()
+            .zip(extent.csum.iter().copied())
+            .collect();
+        let mut stored = Vec::with_capacity(places.len() * io_size as usize);
+        for page in self.read_stored_pages(&places, hasher, cipher).await? {
+            stored.extend(page?);
+        }
+        stored.truncate(extent.stored_len as usize);
+        decompress(extent.algo, &stored, (extent.pages * io_size) as usize)
+    }
+
+    /// read one logical page of a striped chunk, the stripe is
+    /// reconstructed if its data shard is unreadable
+    async fn read_striped_page(
+        &self,
+        chunk_meta: &ChunkMeta,
+        geo: StripeGeometry,
+        hasher: &Hasher,
+        page: u64,
+        out: &mut [u8],
+    ) -> Result<()> {
+        let k = geo.data_shards as u64;
+        let stripe = match chunk_meta.stripes.get(&(page / k)) {
+            Some(stripe) => stripe,
+            None => return Err(EngineError::ReadOutRange),
+        };
+        let cipher = self.chunk_cipher(chunk_meta)?;
+        let idx = (page % k) as usize;
+        let ret = Self::read_stored_page(
+            self,
+            &stripe.shards[idx],
+            stripe.csum[idx],
+            hasher,
+            cipher,
+            out,
+        )
+        .await;
+        if let Err(e) = ret {
+            warn!("reconstruct stripe {}, shard {}: {}", page / k, idx, e);
+            let shards = Self::read_stripe(self, stripe, geo, hasher, cipher).await?;
+            out.copy_from_slice(&shards[idx]);
+        }
+        Ok(())
+    }
+
+    /// read all shards of a stripe, missing or corrupt ones are reconstructed
+    ///
+    /// data shards are read in one batch, parity shards only if any data
+    /// shard is bad
+    async fn read_stripe(
+        &self,
+        stripe: &Stripe,
+        geo: StripeGeometry,
+        hasher: &Hasher,
+        cipher: Option<&Xts>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let k = geo.data_shards;
+        let places: Vec<(&PagePos, u64)> = stripe
+            .shards
+            .iter()
+            .zip(stripe.csum.iter().copied())
+            .collect();
+        let mut shards: Vec<Option<Vec<u8>>> = vec![None; geo.total_shards()];
+        for range in [0..k, k..geo.total_shards()] {
+            if shards.iter().flatten().count() == k {
+                break;
+            }
+            let rets = self
+                .read_stored_pages(&places[range.clone()], hasher, cipher)
+                .await?;
+            for (i, ret) in range.zip(rets) {
+                match ret {
+                    Ok(buf) => shards[i] = Some(buf),
+                    Err(e) => warn!("bad shard {} of stripe: {}", i, e),
+                }
+            }
+        }
+        ErasureCode::new(geo)?.reconstruct(&mut shards)?;
+        Ok(shards.into_iter().map(|s| s.unwrap()).collect())
+    }
+
+    /// write a striped chunk
+    ///
+    /// every touched stripe is merged, encoded with fresh parity and written
+    /// to new positions, one shard per blobstore where possible
+    async fn write_striped(
+        &self,
+        name: String,
+        chunk_meta: ChunkMeta,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let io_size = IO_SIZE;
+        let len = data.len() as u64;
+        let geo = chunk_meta.stripe.unwrap();
+        let code = ErasureCode::new(geo)?;
+        let stripe_len = geo.data_shards as u64 * io_size;
+        let first = offset / stripe_len;
+        let last = (offset + len - 1) / stripe_len;
+        let hasher = &Hasher::with_algo(chunk_meta.csum_type);
+        let cipher = self.chunk_cipher(&chunk_meta)?;
+
+        // merge new data into the old stripes, which are read concurrently
+        let stripes = &chunk_meta.stripes;
+        let olds = self
+            .fan_out((first..=last).map(|index| async move {
+                match stripes.get(&index) {
+                    Some(stripe) => Ok(Some(
+                        Self::read_stripe(self, stripe, geo, hasher, cipher).await?,
+                    )),
+                    None => Ok(None),
+                }
+            }))
+            .await?;
+        let mut images = vec![];
+        for (index, old) in (first..=last).zip(olds) {
+            let mut raw: Vec<u8> = match old {
+                Some(shards) => shards.into_iter().take(geo.data_shards).flatten().collect(),
+                None => vec![0u8; stripe_len as usize],
+            };
+            let stripe_start = index * stripe_len;
+            let from = offset.max(stripe_start);
+            let to = (offset + len).min(stripe_start + stripe_len);
+            raw[(from - stripe_start) as usize..(to - stripe_start) as usize]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            images.push((index, code.encode(&raw)?));
+        }
+
+        let class = self.size_class(&chunk_meta, len);
+        let new_places = self
+            .allocate_class(images.len() as u64, geo.total_shards(), class)
+            .await?;
+        let new_pages: Vec<PagePos> = new_places
+            .iter()
+            .flatten()
+            .map(|place| PagePos::from_places(&[*place]))
+            .collect();
+
+        // shards of all stripes are written in batches
+        let writes = new_pages
+            .iter()
+            .cloned()
+            .zip(images.iter().flat_map(|(_, shards)| shards.iter()))
+            .map(|(pos, shard)| (pos, shard.as_slice()))
+            .collect();
+        let mut written = match self.write_stored_pages(writes, hasher, cipher).await {
+            Ok(written) => written.into_iter(),
+            Err(e) => return self.abort_write(new_pages, e),
+        };
+        let mut stripes = vec![];
+        for (index, shards) in images.iter() {
+            let mut stripe = Stripe {
+                shards: vec![],
+                csum: vec![],
+            };
+            for _ in shards.iter() {
+                let (pos, csum) = written.next().unwrap();
+                stripe.csum.push(csum);
+                stripe.shards.push(pos);
+            }
+            stripes.push((*index, stripe));
+        }
+        self.commit_write(&name, offset + len, new_pages, |chunk_meta| {
+            let mut old_poses = vec![];
+            for (index, stripe) in stripes {
+                if let Some(old) = chunk_meta.stripes.insert(index, stripe) {
+                    old_poses.extend(old.shards);
+                }
+            }
+            old_poses
+        })
+    }
+
+    /// write file
+    ///
+    /// unaligned writes, and small writes in deferred mode, go to the
+    /// write-back buffer if it is enabled, other writes flush the chunk's
+    /// buffer first
+    pub async fn write(&self, name: String, offset: u64, data: &[u8]) -> Result<()> {
+        self.check_open()?;
+        Self::check_chunk_name(&name)?;
+        self.fg_ops.fetch_add(1, Ordering::Relaxed);
+        if self
+            .write_buffer
+            .accepts(offset, data.len() as u64, IO_SIZE)
+        {
+            return Self::buffer_write(self, name, offset, data).await;
+        }
+        self.fsync(name.clone()).await?;
+        Self::write_direct(self, name, offset, data).await
+    }
+
+    /// journal a write and keep it in the write-back buffer
+    async fn buffer_write(&self, name: String, offset: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        // `close` waits for the record to reach the buffer before flushing
+        // it, the lock is released before the chunk is flushed
+        let range = self
+            .locks
+            .lock(&name, offset, offset + data.len() as u64, false)
+            .await?;
+        let chunk_meta = self.db.get_meta(&name)?;
+        if chunk_meta.is_none() {
+            return Err(EngineError::MetaNotExist);
+        }
+        let chunk_meta: ChunkMeta = serde_json::from_slice(&chunk_meta.unwrap())?;
+        if offset > chunk_meta.size.max(self.write_buffer.end(&name)) {
+            return Err(EngineError::HoleNotAllowed);
+        }
+        let seq = self.journal_seq.fetch_add(1, Ordering::SeqCst);
+        let record = JournalRecord {
+            offset,
+            data: data.to_vec(),
+        };
+        self.db
+            .put_journal(&name, seq, &bincode::serialize(&record).unwrap())?;
+        let full = self.write_buffer.insert(&name, seq, offset, data);
+        drop(range);
+        if full {
+            self.fsync(name).await?;
+        }
+        Ok(())
+    }
+
+    /// flush buffered writes of a chunk as merged writes, then drop their
+    /// journal records
+    pub async fn fsync(&self, name: String) -> Result<()> {
+        self.check_open()?;
+        self.flush_buffer(name, false).await
+    }
+
+    /// flush buffered writes of a chunk, `drained` is set once the range
+    /// locks are closed and no access is left in flight, ranges are then
+    /// written without locking
+    ///
+    /// flushes of a chunk are serialized, a later snapshot is never
+    /// overwritten by an earlier one
+    async fn flush_buffer(&self, name: String, drained: bool) -> Result<()> {
+        let _flush = self.write_buffer.flush_lock(&name).await;
+        let buffer = match self.write_buffer.snapshot(&name) {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
+        for (offset, data) in buffer.ranges.iter() {
+            if drained {
+                Self::write_locked(self, name.clone(), *offset, data).await?;
+            } else {
+                Self::write_direct(self, name.clone(), *offset, data).await?;
+            }
+        }
+        let mut batch = WriteBatch::default();
+        for seq in buffer.seqs.iter() {
+            self.db.batch_delete_journal(&mut batch, &name, *seq);
+        }
+        self.db.write(batch)?;
+        self.write_buffer.flushed(&name, &buffer);
+        Ok(())
+    }
+
+    /// flush chunks buffered for longer than the flush interval
+    pub async fn flush_expired(&self) -> Result<()> {
+        self.check_open()?;
+        for name in self.write_buffer.expired() {
+            self.fsync(name).await?;
+        }
+        Ok(())
+    }
+
+    /// start a thread flushing expired chunks periodically, in deferred
+    /// mode it wakes up as soon as a write is journaled
+    ///
+    /// it stops once the engine is closed or dropped, the engine is only
+    /// held while one chunk is flushed
+    fn start_flusher(engine: &Arc<Self>) {
+        let interval = engine.write_buffer.interval;
+        let write_buffer = engine.write_buffer.clone();
+        let engine = Arc::downgrade(engine);
+        let open = |engine: &Weak<Self>| match engine.upgrade() {
+            Some(engine) if !engine.closed.load(Ordering::SeqCst) => Some(engine),
+            _ => None,
+        };
+        std::thread::spawn(move || loop {
+            write_buffer.wait(interval / 2);
+            if open(&engine).is_none() {
+                return;
+            }
+            for name in write_buffer.expired() {
+                let engine = match open(&engine) {
+                    Some(engine) => engine,
+                    None => return,
+                };
+                if let Err(e) = futures::executor::block_on(engine.fsync(name)) {
+                    error!("fail to flush write buffer: {}", e);
+                    drop(engine);
+                    std::thread::sleep(interval / 2);
+                    break;
+                }
+            }
+        });
+    }
+
+    /// start a background thread writing page repairs queued by reads,
+    /// it exits once the engine is closed or dropped
+    fn start_repairer(engine: &Arc<Self>) {
+        let engine = Arc::downgrade(engine);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(REPAIR_INTERVAL);
+            let engine = match engine.upgrade() {
+                Some(engine) if !engine.closed.load(Ordering::SeqCst) => engine,
+                _ => return,
+            };
+            if let Err(e) = futures::executor::block_on(engine.repair_pages()) {
+                error!("fail to repair pages: {}", e);
+            }
+        });
+    }
+
+    /// rewrite a chunk so its pages form contiguous runs
+    ///
+    /// the chunk is rewritten window by window under the window's range lock,
+    /// each fragmented range of mapped pages is swapped in with its own
+    /// metadata commit, holes stay unmapped; striped chunks are left as they
+    /// are
+    ///
+    /// there is no commit for the whole chunk, a pass that fails or stops
+    /// early leaves it partly rewritten, every page is still valid and the
+    /// next pass skips ranges already stored as one run
+    pub async fn defrag(&self, name: String) -> Result<()> {
+        Self::defrag_chunk(self, &name, false).await.map(|_| ())
+    }
+
+    /// return false if `yield_to_load` is set and it stops early because
+    /// foreground reads or writes come in
+    async fn defrag_chunk(&self, name: &str, yield_to_load: bool) -> Result<bool> {
+        self.check_open()?;
+        let window = DEFRAG_WINDOW * IO_SIZE;
+        let mut offset = 0;
+        loop {
+            let ops = self.fg_ops.load(Ordering::Relaxed);
+            {
+                let _range = self.locks.lock(name, offset, offset + window, true).await?;
+                let chunk_meta = match self.db.get_meta(name)? {
+                    Some(chunk_meta) => chunk_meta,
+                    None => return Ok(true),
+                };
+                let chunk_meta: ChunkMeta = serde_json::from_slice(&chunk_meta)?;
+                if chunk_meta.stripe.is_some() || offset >= chunk_meta.size {
+                    return Ok(true);
+                }
+                let first = offset / IO_SIZE;
+                let len = window.min(chunk_meta.size - offset);
+                // holes are left as they are, only fragmented runs of
+                // mapped pages are rewritten
+                for pages in chunk_meta.mapped_ranges(first..first + DEFRAG_WINDOW) {
+                    if chunk_meta.page_runs(pages.clone()).1 < 2 {
+                        continue;
+                    }
+                    let start = pages.start * IO_SIZE;
+                    let end = (pages.end * IO_SIZE).min(chunk_meta.size);
+                    let mut data = vec![0u8; (end - start) as usize];
+                    Self::read_flushed(self, &chunk_meta, start, &mut data).await?;
+                    Self::write_locked(self, name.to_string(), start, &data).await?;
+                }
+                offset += len;
+            }
+            if yield_to_load && self.fg_ops.load(Ordering::Relaxed) != ops {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// chunks whose mapped pages average fewer than `min_run_len` pages per
+    /// run, most fragmented first
+    ///
+    /// a rewrite leaves one run per mapped range in each defragmenter
+    /// window, a chunk split into no more runs than that is never picked,
+    /// so a rewritten chunk is not picked again
+    fn fragmented_chunks(&self, min_run_len: u64) -> Result<Vec<String>> {
+        let mut chunks = vec![];
+        self.db.scan_meta(b"", b"", |k, v| {
+            let chunk_meta: ChunkMeta = serde_json::from_slice(v)?;
+            let (pages, runs) = chunk_meta.page_runs(0..u64::MAX);
+            let rewritten: u64 = chunk_meta
+                .mapped_ranges(0..u64::MAX)
+                .iter()
+                .map(|r| (r.end - 1) / DEFRAG_WINDOW - r.start / DEFRAG_WINDOW + 1)
+                .sum();
+            if chunk_meta.stripe.is_none() && runs > rewritten && pages < runs * min_run_len {
+                chunks.push((runs, String::from_utf8_lossy(k).into_owned()));
+            }
+            Ok(true)
+        })?;
+        chunks.sort_by(|a, b| b.0.cmp(&a.0));
+        Ok(chunks.into_iter().map(|(_, name)| name).collect())
+    }
+
+    /// start a thread defragmenting chunks whose pages average fewer than
+    /// `min_run_len` pages per run, see `defrag`
+    ///
+    /// it wakes up every `interval` and rewrites one chunk, rounds with
+    /// foreground reads or writes since the last round are skipped and a
+    /// chunk is left for a later round as soon as they come in; it stops
+    /// once the engine is closed or dropped
+    pub fn start_defragmenter(
+        engine: &Arc<Self>,
+        min_run_len: u64,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        let engine = Arc::downgrade(engine);
+        let mut queue: VecDeque<String> = VecDeque::new();
+        let mut seen = 0;
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let engine = match engine.upgrade() {
+                Some(engine) if !engine.closed.load(Ordering::SeqCst) => engine,
+                _ => break,
+            };
+            let ops = engine.fg_ops.load(Ordering::Relaxed);
+            if std::mem::replace(&mut seen, ops) != ops {
+                continue;
+            }
+            if queue.is_empty() {
+                match engine.fragmented_chunks(min_run_len) {
+                    Ok(chunks) => queue.extend(chunks),
+                    Err(e) => error!("fail to find fragmented chunks: {}", e),
+                }
+            }
+            let name = match queue.pop_front() {
+                Some(name) => name,
+                None => continue,
+            };
+            match futures::executor::block_on(engine.defrag_chunk(&name, true)) {
+                Ok(true) => {}
+                // windows already rewritten are skipped next time
+                Ok(false) => queue.push_back(name),
+                Err(e) => error!("fail to defragment {}: {}", name, e),
+            }
+        })
+    }
+
+    /// write file without the write-back buffer
+    ///
+    /// partially covered head and tail pages are read, merged and written to
+    /// new positions, fully covered pages are written to new positions
+    /// directly and may be stored as one compressed extent
+    async fn write_direct(&self, name: String, offset: u64, data: &[u8]) -> Result<()> {
+        let len = data.len() as u64;
+        if len == 0 {
+            return Ok(());
+        }
+        let (start, end) = self.lock_range(&name, offset, len)?;
+        let _range = self.locks.lock(&name, start, end, true).await?;
+        Self::write_locked(self, name, offset, data).await
+    }
+
+    /// bytes to lock for an access, whole pages, or whole stripes of a
+    /// striped chunk
+    fn lock_range(&self, name: &str, offset: u64, len: u64) -> Result<(u64, u64)> {
+        let chunk_meta = self.db.get_meta(name)?;
+        let unit = match chunk_meta {
+            Some(m) => match serde_json::from_slice::<ChunkMeta>(&m)?.stripe {
+                Some(geo) => geo.data_shards as u64 * IO_SIZE,
+                None => IO_SIZE,
+            },
+            None => IO_SIZE,
+        };
+        Ok((
+            offset / unit * unit,
+            (offset + len + unit - 1) / unit * unit,
+        ))
+    }
+
+    /// write file, caller holds the range lock
+    async fn write_locked(&self, name: String, offset: u64, data: &[u8]) -> Result<()> {
+        let io_size = IO_SIZE;
+        let len = data.len() as u64;
+        if len == 0 {
+            return Ok(());
+        }
+
+        // get and check metadata
+        let chunk_meta = self.db.get_meta(&name)?;
+        if chunk_meta.is_none() {
+            return Err(EngineError::MetaNotExist);
+        }
+        let chunk_meta: ChunkMeta =
+            serde_json::from_slice(String::from_utf8(chunk_meta.unwrap()).unwrap().as_bytes())?;
+        let size = chunk_meta.get_size();
+        if offset > size {
+            return Err(EngineError::HoleNotAllowed);
+        }
+        if chunk_meta.stripe.is_some() {
+            return Self::write_striped(self, name, chunk_meta, offset, data).await;
+        }
+
+        let hasher = Hasher::with_algo(chunk_meta.csum_type);
+        let start_page = offset / io_size;
+        let end_page = (offset + len - 1) / io_size;
+        // pages holding data before this write
+        let page_count = (size + io_size - 1) / io_size;
+        // fully covered pages: [full_start, full_end)
+        let full_start = (offset + io_size - 1) / io_size;
+        let full_end = (offset + len) / io_size;
+        let full_pages = full_end.saturating_sub(full_start);
+
+        // build head and tail pages
+        /*
+            origin: |---|---|---|---|
+            write:     |xxxxxxxxx|
+            edges:  |-xx|       |x--|
+        */
+        let mut edge_pages = vec![];
+        for page in [start_page, end_page] {
+            if !(full_start..full_end).contains(&page) && !edge_pages.contains(&page) {
+                edge_pages.push(page);
+            }
+        }
+        let stored: Vec<u64> = edge_pages
+            .iter()
+            .copied()
+            .filter(|page| *page < page_count)
+            .collect();
+        let mut old = Self::read_pages(self, &chunk_meta, &hasher, &stored)
+            .await?
+            .into_iter();
+        let mut edges: Vec<(u64, Vec<u8>)> = vec![];
+        for page in edge_pages {
+            let mut buf = if page < page_count {
+                old.next().unwrap()
+            } else {
+                vec![0u8; io_size as usize]
+            };
+            let page_start = page * io_size;
+            let from = offset.max(page_start);
+            let to = (offset + len).min(page_start + io_size);
+            buf[(from - page_start) as usize..(to - page_start) as usize]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            edges.push((page, buf));
+        }
+
+        // try to compress fully covered pages, keep them raw if it saves no page
+        let compressed = if full_pages > 1 {
+            let data_start = (full_start * io_size - offset) as usize;
+            let data_end = (full_end * io_size - offset) as usize;
+            compress(self.compress_algo, &data[data_start..data_end])
+                .filter(|c| (c.len() as u64 + io_size - 1) / io_size < full_pages)
+        } else {
+            None
+        };
+        let full_stored_pages = match &compressed {
+            Some(c) => (c.len() as u64 + io_size - 1) / io_size,
+            None => full_pages,
+        };
+        let total_page_num = edges.len() as u64 + full_stored_pages;
+
+        // get all new positions to write new data, old positions are
+        // recycled when the write is committed
+        let class = self.size_class(&chunk_meta, len);
+        let cipher = self.chunk_cipher(&chunk_meta)?;
+        let new_poses = self
+            .allocate_class(total_page_num, self.replicas, class)
+            .await?;
+        let new_pages: Vec<PagePos> = new_poses.iter().map(|p| PagePos::from_places(p)).collect();
+
+        // stored pages of the compressed extent, zero padded
+        let extent_pages: Vec<Vec<u8>> = match &compressed {
+            Some(c) => c
+                .chunks(io_size as usize)
+                .map(|stored| {
+                    let mut buf = vec![0u8; io_size as usize];
+                    buf[..stored.len()].copy_from_slice(stored);
+                    buf
+                })
+                .collect(),
+            None => vec![],
+        };
+        // (logical page, image) to write, extent pages have no logical page
+        let mut images: Vec<(Option<u64>, &[u8])> = edges
+            .iter()
+            .map(|(page, buf)| (Some(*page), buf.as_slice()))
+            .collect();
+        match compressed {
+            Some(_) => images.extend(extent_pages.iter().map(|buf| (None, buf.as_slice()))),
+            None => {
+                for page in full_start..full_end {
+                    let data_start = (page * io_size - offset) as usize;
+                    images.push((Some(page), &data[data_start..data_start + io_size as usize]));
+                }
+            }
+        }
+
+        // pages are encrypted before checksumming, read verifies then decrypts,
+        // all pages are written in batches
+        let (logical, images): (Vec<Option<u64>>, Vec<&[u8]>) = images.into_iter().unzip();
+        let writes = new_pages.iter().cloned().zip(images).collect();
+        let written = match self.write_stored_pages(writes, &hasher, cipher).await {
+            Ok(written) => written,
+            Err(e) => return self.abort_write(new_pages, e),
+        };
+
+        // (page, position, checksum) of raw pages
+        let mut raw_pages = vec![];
+        let mut new_extent = compressed.map(|c| Extent {
+            first_page: full_start,
+            pages: full_pages,
+            algo: self.compress_algo,
+            stored_len: c.len() as u64,
+            poses: vec![],
+            csum: vec![],
+            refs: full_pages,
+        });
+        for (page, (pos, csum)) in logical.into_iter().zip(written) {
+            match (page, new_extent.as_mut()) {
+                (Some(page), _) => raw_pages.push((page, pos, csum)),
+                (None, Some(extent)) => {
+                    extent.csum.push(csum);
+                    extent.poses.push(pos);
+                }
+                (None, None) => unreachable!(),
+            }
+        }
+
+        // map new pages on the latest metadata, unmap overwritten pages,
+        // a compressed extent is released once none of its pages is mapped
+        self.commit_write(&name, offset + len, new_pages, |chunk_meta| {
+            let mut old_poses = vec![];
+            for page in start_page..=end_page {
+                old_poses.extend(chunk_meta.unmap_page(page));
+            }
+            let mut locations = chunk_meta.location.take().unwrap_or_default();
+            let checksum_vec = &mut chunk_meta.csum_data;
+            if checksum_vec.len() <= end_page as usize {
+                checksum_vec.resize(end_page as usize + 1, 0);
+            }
+            for (page, pos, csum) in raw_pages {
+                checksum_vec[page as usize] = csum;
+                locations.insert(page, pos);
+            }
+            if let Some(extent) = new_extent {
+                let id = chunk_meta.next_extent;
+                chunk_meta.next_extent += 1;
+                for page in full_start..full_end {
+                    locations.insert(
+                        page,
+                        PagePos {
+                            extent: Some(id),
+                            ..extent.poses[0].clone()
+                        },
+                    );
+                    checksum_vec[page as usize] = 0;
+                }
+                chunk_meta.extents.insert(id, extent);
+            }
+            chunk_meta.location = Some(locations);
+            old_poses
+        })
+    }
+
+    /// apply a write to the latest metadata of a chunk
+    ///
+    /// `apply` maps the new pages and returns the pages they replace, which
+    /// are freed in the same batch; the new pages are freed instead if the
+    /// chunk is gone or the batch fails
+    fn commit_write<F>(&self, name: &str, end: u64, new_pages: Vec<PagePos>, apply: F) -> Result<()>
+    where
+        F: FnOnce(&mut ChunkMeta) -> Vec<PagePos>,
+    {
+        let _meta = self.locks.meta(name);
+        let mut l = self.mad_engine.lock().unwrap();
+        let ret = self.get_global_meta().and_then(|mut global_meta| {
+            let chunk_meta = match self.db.get_meta(name)? {
+                Some(chunk_meta) => chunk_meta,
+                None => return Err(EngineError::MetaNotExist),
+            };
+            let mut chunk_meta: ChunkMeta = serde_json::from_slice(&chunk_meta)?;
+            let old_poses = apply(&mut chunk_meta);
+            chunk_meta.size = chunk_meta.size.max(end);
+
+            let sizes = self.recycle_global(&mut global_meta, &old_poses);
+            let mut batch = WriteBatch::default();
+            batch.put(
+                Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
+                serde_json::to_string(&global_meta).unwrap().as_bytes(),
+            );
+            self.db.batch_put_meta(
+                &mut batch,
+                name,
+                serde_json::to_string(&chunk_meta).unwrap().as_bytes(),
+            );
+            self.db.write(batch)?;
+            Ok((global_meta.free_list, old_poses, sizes))
+        });
+        match ret {
+            Ok((free_list, old_poses, sizes)) => {
+                l.free_list = free_list;
+                drop(l);
+                self.release_local(old_poses, sizes);
+                Ok(())
+            }
+            Err(e) => {
+                drop(l);
+                self.abort_write(new_pages, e)
+            }
+        }
+    }
+
+    /// free the pages of a write that is not committed and return its error
+    fn abort_write<T>(&self, new_pages: Vec<PagePos>, err: EngineError) -> Result<T> {
+        if let Err(e) = self.free_pages(new_pages) {
+            error!("fail to free pages of an aborted write: {}", e);
+        }
+        Err(err)
+    }
+
+    /// free pages no chunk maps
+    fn free_pages(&self, pages: Vec<PagePos>) -> Result<()> {
+        let sizes = {
+            let mut l = self.mad_engine.lock().unwrap();
+            let mut global_meta = self.get_global_meta()?;
+            let sizes = self.recycle_global(&mut global_meta, &pages);
+            self.db.put(
+                Hasher::new().checksum(MAGIC.as_bytes()).to_string(),
+                serde_json::to_string(&global_meta).unwrap().as_bytes(),
+            )?;
+            l.free_list = global_meta.free_list;
+            sizes
+        };
+        self.release_local(pages, sizes);
+        Ok(())
+    }
+
+    /// read a chunk, this should return the read length
+    ///
+    /// buffered writes are copied over the data read from blobs
+    ///
+    /// TODO: check read range, return read length
+    pub async fn read(&self, name: String, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.check_open()?;
+        self.fg_ops.fetch_add(1, Ordering::Relaxed);
+        let len = data.len() as u64;
+        if len == 0 {
+            return Ok(());
+        }
+
+        // pages in range are neither overwritten nor freed while reading
+        let (start, end) = self.lock_range(&name, offset, len)?;
+        let _range = self.locks.lock(&name, start, end, false).await?;
+        let chunk_meta = self.db.get_meta(&name)?;
+        if chunk_meta.is_none() {
+            return Err(EngineError::MetaNotExist);
+        }
+        let chunk_meta: ChunkMeta =
+            serde_json::from_slice(String::from_utf8(chunk_meta.unwrap()).unwrap().as_bytes())?;
+        let buffered = self.write_buffer.snapshot(&name);
+        let size = chunk_meta
+            .size
+            .max(buffered.as_ref().map_or(0, |b| b.end()));
+        if offset >= size || offset + len > size {
+            return Err(EngineError::ReadOutRange);
+        }
+        // bytes beyond the flushed size only live in the buffer
+        let flushed_len = chunk_meta.size.saturating_sub(offset).min(len);
+        data[flushed_len as usize..].fill(0);
+        if flushed_len > 0 {
+            Self::read_flushed(self, &chunk_meta, offset, &mut data[..flushed_len as usize])
+                .await?;
+        }
+        if let Some(buffered) = buffered {
+            buffered.overlay(offset, data);
+        }
+        Ok(())
+    }
+
+    /// read flushed data of a chunk
+    async fn read_flushed(
+        &self,
+        chunk_meta: &ChunkMeta,
+        offset: u64,
+        data: &mut [u8],
+    ) -> Result<()> {
+        let len = data.len() as u64;
+        let io_size = IO_SIZE;
+        let start_page = offset / io_size;
+        let end_page = (offset + len - 1) / io_size;
+
+        // pages are read concurrently, then copied out
+        let hasher = Hasher::with_algo(chunk_meta.csum_type);
+        let pages: Vec<u64> = (start_page..=end_page).collect();
+        let bufs = Self::read_pages(self, chunk_meta, &hasher, &pages).await?;
+        for (page, buf) in pages.into_iter().zip(bufs) {
+            let page_start = page * io_size;
+            let from = offset.max(page_start);
+            let to = (offset + len).min(page_start + io_size);
+            data[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&buf[(from - page_start) as usize..(to - page_start) as usize]);
+        }
+        Ok(())
+    }
+
+    /// get the cipher of a chunk, none if the chunk is not encrypted
+    fn chunk_cipher(&self, chunk_meta: &ChunkMeta) -> Result<Option<&Xts>> {
+        match chunk_meta.key_version {
+            None => Ok(None),
+            Some(v) => match self.keys.get(&v) {
+                Some(cipher) => Ok(Some(cipher)),
+                None => Err(EngineError::KeyErr(format!("key version {} not loaded", v))),
+            },
+        }
+    }
+
+    /// get page cache statistics, none if the cache is disabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.page_cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// unload blobstore, blobstores already unloaded are skipped so a
+    /// failed call can be retried
+    pub async fn unload_bs(&self) -> Result<()> {
+        let mut unloaded = self.unloaded.lock().await;
+        for (be, unloaded) in self.blob_engines.iter().zip(unloaded.iter_mut()) {
+            if !*unloaded {
+                be.unload().await?;
+                *unloaded = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// wait for in-flight I/O, flush buffered writes, flush RocksDB and
+    /// unload every blobstore, then close RocksDB, unload blobfs and stop
+    /// the SPDK app
+    ///
+    /// blobs are opened per I/O, so none is left open once I/O is drained;
+    /// I/O started afterwards fails with `Closed`. Other holders of the
+    /// engine have `timeout` to drop it, otherwise it fails with `Busy` and
+    /// RocksDB is closed and the app stopped when the last one drops it
+    pub async fn close(self: Arc<Self>, timeout: Duration) -> Result<()> {
+        self.shutdown().await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut engine = self;
+        let mut engine = loop {
+            match Arc::try_unwrap(engine) {
+                Ok(engine) => break engine,
+                Err(shared) => engine = shared,
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(EngineError::Busy);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let app = engine.app.take();
+        // RocksDB lives on blobfs, close it first
+        drop(engine);
+        if let Some(app) = app {
+            app.stop().await;
+        }
+        Ok(())
+    }
+
+    /// close the engine as `close` does on the first SIGINT or SIGTERM
+    ///
+    /// SPDK's signal handlers are replaced, call it once the engine is
+    /// open. I/O started after the signal fails with `Closed`, other holders
+    /// of `engine` have `grace` to drop it, the process aborts on a second
+    /// signal or once `grace` runs out
+    pub async fn close_on_signal(engine: Arc<Self>, grace: Duration) -> Result<()> {
+        shutdown_signal()?.await;
+        info!("shutdown signal received, close engine in {:?}", grace);
+        match tokio::time::timeout(grace, engine.close(grace)).await {
+            Ok(Err(EngineError::Busy)) | Err(_) => {
+                error!("engine is not closed in {:?}, abort", grace);
+                std::process::abort();
+            }
+            Ok(res) => res,
+        }
+    }
+
+    /// everything `close` does before RocksDB is closed
+    ///
+    /// once it succeeds later calls do nothing, after a failure the next
+    /// call retries the steps left
+    async fn shutdown(&self) -> Result<()> {
+        let mut shut_down = self.shut_down.lock().await;
+        if *shut_down {
+            return Ok(());
+        }
+        // refuse new operations and wait for those in flight, nothing is
+        // added to the write buffer afterwards
+        self.closed.store(true, Ordering::SeqCst);
+        self.locks.close().await;
+        // leave nothing to replay from the journal on reload
+        for name in self.write_buffer.chunks() {
+            self.flush_buffer(name, true).await?;
+        }
+        self.db.flush()?;
+        self.unload_bs().await?;
+        *shut_down = true;
+        Ok(())
+    }
+
+    /// fail with `Closed` once shutdown starts
+    fn check_open(&self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(EngineError::Closed);
+        }
         Ok(())
     }
 
@@ -776,61 +2596,51 @@ impl FileEngine {
     ///
     /// todo:
     pub fn info(&self) -> Result<FsInfo> {
+        self.check_open()?;
         let size = self.mad_engine.lock().unwrap().device.cluster_size;
         let total = self.mad_engine.lock().unwrap().device.total_cluster;
-        let free = total - self.mad_engine.lock().unwrap().blob_size * NUM_THREAD as u64;
+        let workers = self.alloc.states().len() as u64;
+        let free = total - self.mad_engine.lock().unwrap().blob_size * workers;
         Ok(FsInfo::set(size, total, free))
     }
 
     /// resize a file
     pub async fn resize(&self, name: String, len: u64) -> Result<()> {
-        let mut chunk_meta = self.db.get(name.clone())?;
+        self.check_open()?;
+        self.fsync(name.clone()).await?;
+        let _range = self.locks.lock(&name, 0, u64::MAX, true).await?;
+        let mut chunk_meta = self.db.get_meta(name.clone())?;
         let io_size = IO_SIZE;
         if chunk_meta.is_none() {
             self.create(name.clone())?;
-            chunk_meta = self.db.get(name.clone())?;
+            chunk_meta = self.db.get_meta(name.clone())?;
         }
-        let mut chunk_meta: ChunkMeta =
+        let chunk_meta: ChunkMeta =
             serde_json::from_slice(String::from_utf8(chunk_meta.unwrap()).unwrap().as_bytes())?;
         let size = chunk_meta.size;
         if len == size {
             return Ok(());
         } else if len > size {
             let data = vec![0u8; (len - size) as usize];
-            self.write(name.clone(), size, data.as_ref()).await?;
+            Self::write_locked(self, name.clone(), size, data.as_ref()).await?;
         } else {
-            if len % io_size == 0 {
-                chunk_meta.size = len;
-                self.db.put(
-                    name.clone(),
-                    serde_json::to_string(&chunk_meta).unwrap().as_bytes(),
-                )?;
-                return Ok(());
+            // zero the rest of the new last page (or stripe), it goes
+            // through the normal copy-on-write path
+            let unit = match chunk_meta.stripe {
+                Some(geo) => geo.data_shards as u64 * io_size,
+                None => io_size,
+            };
+            if len % unit != 0 {
+                let unit_end = (len / unit + 1) * unit;
+                let data = vec![0u8; (unit_end.min(size) - len) as usize];
+                Self::write_locked(self, name.clone(), len, data.as_ref()).await?;
             }
-            chunk_meta.size = len;
-            let last_page = len as i64 / io_size as i64;
-            let data = vec![0u8; (io_size - (len - last_page as u64 * io_size)) as usize];
-            let mut buf = DmaBuf::alloc((io_size) as usize, 0x1000);
-            let pos = chunk_meta
-                .location
-                .clone()
-                .unwrap()
-                .get(&(last_page as u64))
-                .unwrap()
-                .clone();
-            self.blob_engine
-                .clone()
-                .read(pos.offset, pos.bid, buf.as_mut())
-                .await
-                .unwrap();
-            let buffer = buf.as_mut();
-            buffer[(len - last_page as u64 * io_size) as usize..].copy_from_slice(&data[..]);
-            chunk_meta.csum_data[last_page as usize] = Hasher::new().checksum(buffer.as_ref());
-            self.blob_engine.write(pos.offset, pos.bid, buffer).await?;
-            self.db.put(
-                name.clone(),
-                serde_json::to_string(&chunk_meta).unwrap().as_bytes(),
-            )?;
+            // pages beyond the new size stay mapped until they are
+            // overwritten or the file is removed
+            self.commit_write(&name, 0, vec![], |chunk_meta| {
+                chunk_meta.size = len;
+                vec![]
+            })?;
         }
         Ok(())
     }
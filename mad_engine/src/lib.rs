@@ -17,6 +17,9 @@ pub use blob_engine::*;
 pub mod message;
 pub use message::*;
 
+pub mod core_channel;
+pub use core_channel::*;
+
 pub mod transactiondb_engine;
 pub use transactiondb_engine::*;
 
@@ -27,3 +30,18 @@ pub mod engine_trait;
 
 pub mod db_engine;
 pub use db_engine::*;
+
+pub mod write_buffer;
+pub use write_buffer::*;
+
+pub mod chunk_lock;
+pub use chunk_lock::*;
+
+pub mod alloc_pool;
+pub use alloc_pool::*;
+
+pub mod page_repair;
+pub use page_repair::*;
+
+pub mod signal;
+pub use signal::*;
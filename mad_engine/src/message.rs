@@ -1,8 +1,10 @@
 //! This includes I/O operation based on blob
 
-use async_spdk::blob::{Blob, BlobId, Blobstore, IoChannel};
-use std::sync::{Arc, Mutex};
-use tokio::sync::Notify;
+use crate::CoreBound;
+use async_spdk::blob::{Blob, BlobId, Blobstore};
+use async_spdk::env::DmaBuf;
+use async_spdk::SpdkError;
+use std::sync::Arc;
 
 pub enum Op {
     /// Get I/O size, unimplemented
@@ -29,182 +31,201 @@ pub enum Op {
     Resize,
     /// Close a blob
     Close,
+    /// Read and write several blobs with one event
+    Batch,
 }
 
-pub struct Msg<'a> {
+/// One read or write of a batch, the buffer is handed back with its status
+pub enum BlobIo {
+    /// Read a blob at offset into the buffer
+    Read(BlobId, u64, DmaBuf),
+    /// Write the buffer to a blob at offset
+    Write(BlobId, u64, DmaBuf),
+}
+
+impl BlobIo {
+    /// blob the I/O goes to
+    pub fn blob_id(&self) -> BlobId {
+        match self {
+            BlobIo::Read(bid, _, _) => *bid,
+            BlobIo::Write(bid, _, _) => *bid,
+        }
+    }
+
+    /// take the buffer back
+    pub fn into_buf(self) -> DmaBuf {
+        match self {
+            BlobIo::Read(_, _, buf) => buf,
+            BlobIo::Write(_, _, buf) => buf,
+        }
+    }
+}
+
+/// What an operation hands back on success
+pub enum Done {
+    /// Nothing to hand back
+    Unit,
+    /// Id of a created blob
+    BlobId(BlobId),
+    /// Handle of an opened blob
+    Blob(Blob),
+    /// Buffer of a read or write
+    Buf(DmaBuf),
+    /// Each batched I/O with its status, in submission order
+    Batch(Vec<(BlobIo, Result<(), SpdkError>)>),
+}
+
+pub struct Msg {
     pub op: Op,
-    #[allow(unused)]
-    channel: Option<IoChannel>,
-    #[allow(unused)]
-    notify: Option<Arc<Notify>>,
-    pub bs: Option<Arc<Mutex<Blobstore>>>,
+    pub bs: Option<Arc<CoreBound<Blobstore>>>,
     pub offset: Option<u64>,
     pub blob_id: Option<BlobId>,
     pub blob: Option<Blob>,
-    pub read_buf: Option<&'a mut [u8]>,
-    pub write_buf: Option<&'a [u8]>,
+    // buffer read into or written from
+    pub buf: Option<DmaBuf>,
     pub blob_size: Option<u64>,
+    // opened blob and I/O of each batched I/O
+    pub batch: Option<Vec<(Blob, BlobIo)>>,
 }
 
-impl<'a> Msg<'a> {
+impl Msg {
     /// close blob
-    pub fn gen_close(notify: Arc<Notify>, bs: Arc<Mutex<Blobstore>>, blob: Blob) -> Self {
+    pub fn gen_close(bs: Arc<CoreBound<Blobstore>>, blob: Blob) -> Self {
         Self {
             op: Op::Close,
-            channel: None,
-            notify: Some(notify),
             bs: Some(bs),
             offset: None,
             blob_id: None,
             blob: Some(blob),
-            read_buf: None,
-            write_buf: None,
+            buf: None,
             blob_size: None,
+            batch: None,
         }
     }
 
     /// unload blobstore
-    pub fn gen_unload(notify: Arc<Notify>, bs: Arc<Mutex<Blobstore>>) -> Self {
+    pub fn gen_unload(bs: Arc<CoreBound<Blobstore>>) -> Self {
         Self {
             op: Op::Unload,
-            channel: None,
-            notify: Some(notify),
             bs: Some(bs),
             offset: None,
             blob_id: None,
             blob: None,
-            read_buf: None,
-            write_buf: None,
+            buf: None,
             blob_size: None,
+            batch: None,
         }
     }
 
     /// write blob
-    pub fn gen_write(
-        notify: Arc<Notify>,
-        bs: Arc<Mutex<Blobstore>>,
-        offset: u64,
-        blob: Blob,
-        buf: &'a [u8],
-    ) -> Self {
+    pub fn gen_write(bs: Arc<CoreBound<Blobstore>>, offset: u64, blob: Blob, buf: DmaBuf) -> Self {
         Self {
             op: Op::Write,
-            channel: None,
-            notify: Some(notify),
             bs: Some(bs),
             offset: Some(offset),
             blob_id: None,
             blob: Some(blob),
-            read_buf: None,
-            write_buf: Some(buf),
+            buf: Some(buf),
             blob_size: None,
+            batch: None,
         }
     }
 
     /// read blob
-    pub fn gen_read(
-        notify: Arc<Notify>,
-        bs: Arc<Mutex<Blobstore>>,
-        offset: u64,
-        blob: Blob,
-        buf: &'a mut [u8],
-    ) -> Self {
+    pub fn gen_read(bs: Arc<CoreBound<Blobstore>>, offset: u64, blob: Blob, buf: DmaBuf) -> Self {
         Self {
             op: Op::Read,
-            channel: None,
-            notify: Some(notify),
             bs: Some(bs),
             offset: Some(offset),
             blob_id: None,
             blob: Some(blob),
-            read_buf: Some(buf),
-            write_buf: None,
+            buf: Some(buf),
             blob_size: None,
+            batch: None,
         }
     }
 
     /// create blob
-    pub fn gen_create(notify: Arc<Notify>, bs: Arc<Mutex<Blobstore>>) -> Self {
+    pub fn gen_create(bs: Arc<CoreBound<Blobstore>>) -> Self {
         Self {
             op: Op::Create,
-            channel: None,
-            notify: Some(notify),
             bs: Some(bs),
             offset: None,
             blob_id: None,
             blob: None,
-            read_buf: None,
-            write_buf: None,
+            buf: None,
             blob_size: None,
+            batch: None,
         }
     }
 
     /// delete blob
-    pub fn gen_delete(notify: Arc<Notify>, bs: Arc<Mutex<Blobstore>>, blob_id: BlobId) -> Self {
+    pub fn gen_delete(bs: Arc<CoreBound<Blobstore>>, blob_id: BlobId) -> Self {
         Self {
             op: Op::Delete,
-            channel: None,
-            notify: Some(notify),
             bs: Some(bs),
             offset: None,
             blob_id: Some(blob_id),
             blob: None,
-            read_buf: None,
-            write_buf: None,
+            buf: None,
             blob_size: None,
+            batch: None,
         }
     }
 
     /// open blob
-    pub fn gen_open(notify: Arc<Notify>, bs: Arc<Mutex<Blobstore>>, bid: BlobId) -> Self {
+    pub fn gen_open(bs: Arc<CoreBound<Blobstore>>, bid: BlobId) -> Self {
         Self {
             op: Op::Open,
-            channel: None,
-            notify: Some(notify),
             bs: Some(bs),
             offset: None,
             blob_id: Some(bid),
             blob: None,
-            read_buf: None,
-            write_buf: None,
+            buf: None,
             blob_size: None,
+            batch: None,
         }
     }
 
     /// sync blob
-    pub fn gen_sync(notify: Arc<Notify>, bs: Arc<Mutex<Blobstore>>, blob: Blob) -> Self {
+    pub fn gen_sync(bs: Arc<CoreBound<Blobstore>>, blob: Blob) -> Self {
         Self {
             op: Op::Sync,
-            channel: None,
-            notify: Some(notify),
             bs: Some(bs),
             offset: None,
             blob_id: None,
             blob: Some(blob),
-            read_buf: None,
-            write_buf: None,
+            buf: None,
             blob_size: None,
+            batch: None,
         }
     }
 
     /// resize blob
-    pub fn gen_resize(
-        notify: Arc<Notify>,
-        bs: Arc<Mutex<Blobstore>>,
-        blob: Blob,
-        size: u64,
-    ) -> Self {
+    pub fn gen_resize(bs: Arc<CoreBound<Blobstore>>, blob: Blob, size: u64) -> Self {
         Self {
             op: Op::Resize,
-            channel: None,
-            notify: Some(notify),
             bs: Some(bs),
             offset: None,
             blob_id: None,
             blob: Some(blob),
-            read_buf: None,
-            write_buf: None,
+            buf: None,
             blob_size: Some(size),
+            batch: None,
+        }
+    }
+
+    /// batched reads and writes
+    pub fn gen_batch(bs: Arc<CoreBound<Blobstore>>, batch: Vec<(Blob, BlobIo)>) -> Self {
+        Self {
+            op: Op::Batch,
+            bs: Some(bs),
+            offset: None,
+            blob_id: None,
+            blob: None,
+            buf: None,
+            blob_size: None,
+            batch: Some(batch),
         }
     }
 }
@@ -1,10 +1,12 @@
 //! This is a plugin for establishing SPDK environment
 
 use crate::blob_engine::BlobEngine;
-use crate::error::Result;
+use crate::common::{ClassPolicy, SizeClass};
+use crate::core_channel::{CoreBound, CoreChannel, Request};
+use crate::error::{EngineError, Result};
+use crate::utils::{ChecksumAlgo, CompressAlgo, StripeGeometry, NUM_THREAD};
 use async_spdk::blob::{self, Blobstore};
 use async_spdk::blobfs::SpdkBlobfsOpts;
-use async_spdk::event::SpdkEvent;
 use async_spdk::thread::Poller;
 use async_spdk::{
     blob_bdev,
@@ -12,12 +14,16 @@ use async_spdk::{
     event::{self, app_stop},
 };
 use log::*;
-use std::ffi::{c_void, CString};
 use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
     sync::{Arc, Mutex},
     thread::JoinHandle,
+    time::Duration,
 };
-use tokio::sync::Notify;
+use tokio::sync::watch;
 
 pub struct EngineOpts {
     // start reactor on which core
@@ -31,21 +37,32 @@ pub struct EngineOpts {
     // start each blobstore on specific bdev, there could be multiple blobstore
     blobstore_bdev_list: Option<Vec<BsBindOpts>>,
     // Blobstore list
-    blobstores: Arc<Mutex<Vec<Arc<Mutex<Blobstore>>>>>,
+    blobstores: Arc<Mutex<Vec<Arc<CoreBound<Blobstore>>>>>,
     // SPDK start thread handle
     thread_handle: Option<JoinHandle<()>>,
     // App name
     app_name: String,
-    // Flag to indicate blobfs establish
-    fsflag: Arc<Mutex<bool>>,
-    // Flag to indicate blobstore establish
-    bsflag: Arc<Mutex<bool>>,
+    // Startup progress of blobfs and blobstores
+    startup: watch::Receiver<Startup>,
     // Blobfs pointer
     pub fs: Arc<Mutex<SpdkFilesystem>>,
     // Shutdown signal
     shutdown: Arc<Mutex<bool>>,
     // Shutdown Poller
     shutdown_poller: Arc<Mutex<Poller>>,
+    // the SPDK thread is not joined on drop
+    detached: bool,
+}
+
+/// Startup progress of the SPDK environment
+#[derive(Debug, Clone)]
+enum Startup {
+    /// the component being started
+    Starting(String),
+    /// every component is started
+    Ready,
+    /// the component that failed to start and why
+    Failed(String, String),
 }
 
 /// This defines the mapping between bs to core
@@ -68,17 +85,20 @@ impl Default for EngineOpts {
             blobstores: Arc::new(Mutex::new(vec![])),
             thread_handle: None,
             app_name: String::new(),
-            fsflag: Arc::new(Mutex::new(false)),
-            bsflag: Arc::new(Mutex::new(false)),
+            startup: watch::channel(Startup::Starting("SPDK app".to_string())).1,
             fs: Arc::new(Mutex::new(SpdkFilesystem::default())),
             shutdown: Arc::new(Mutex::new(false)),
             shutdown_poller: Arc::new(Mutex::new(Poller::default())),
+            detached: false,
         }
     }
 }
 
 impl Drop for EngineOpts {
     fn drop(&mut self) {
+        if self.detached {
+            return;
+        }
         if self.thread_handle.is_some() {
             self.thread_handle.take().unwrap().join().unwrap();
         } else {
@@ -131,28 +151,45 @@ impl EngineOpts {
         let blobstores = self.blobstores.clone();
 
         let fs = self.fs.clone();
-        let fsflag = self.fsflag.clone();
-        let bsflag = self.bsflag.clone();
+        let (startup, rx) = watch::channel(Startup::Starting("SPDK app".to_string()));
+        self.startup = rx;
         let shutdown = self.shutdown.clone();
         let shutdown_poller = self.shutdown_poller.clone();
+        let started = self.blobstores.clone();
+        let started_list = self.blobstore_bdev_list.clone().unwrap_or_default();
         let fs_handle = std::thread::spawn(move || {
-            event::AppOpts::new()
+            let res = event::AppOpts::new()
                 .name(app_name.as_str())
                 .config_file(config_file.as_str())
                 .reactor_mask(reactor_mask.as_str())
-                .block_on(Self::start_spdk_helper(
-                    is_reload,
-                    fs,
-                    fsflag,
-                    bsflag,
-                    shutdown,
-                    shutdown_poller,
-                    blobfs_bdev.as_ref(),
-                    start_blobfs,
-                    blobstore_bdev_list.unwrap(),
-                    blobstores,
-                ))
-                .unwrap();
+                .block_on(async {
+                    let res = Self::start_spdk_helper(
+                        is_reload,
+                        fs,
+                        &startup,
+                        shutdown,
+                        shutdown_poller,
+                        blobfs_bdev.as_ref(),
+                        start_blobfs,
+                        blobstore_bdev_list.unwrap_or_default(),
+                        blobstores,
+                    )
+                    .await;
+                    if let Err(e) = &res {
+                        Self::fail_startup(&startup, e.to_string());
+                        // blobstores started before the failure are not
+                        // left dirty
+                        for be in Self::started_bes(&started_list, &started) {
+                            let _ = be.unload().await;
+                        }
+                        app_stop();
+                    }
+                    res
+                });
+            if let Err(e) = res {
+                error!("SPDK app exits with error: {}", e);
+                Self::fail_startup(&startup, e.to_string());
+            }
         });
         self.thread_handle = Some(fs_handle);
     }
@@ -160,25 +197,28 @@ impl EngineOpts {
     async fn start_spdk_helper(
         is_reload: bool,
         fs: Arc<Mutex<SpdkFilesystem>>,
-        fsflag: Arc<Mutex<bool>>,
-        bsflag: Arc<Mutex<bool>>,
+        startup: &watch::Sender<Startup>,
         shutdown: Arc<Mutex<bool>>,
         shutdown_poller: Arc<Mutex<Poller>>,
         blobfs_bdev: Option<&String>,
         start_blobfs: bool,
         blobstore_bdev_list: Vec<BsBindOpts>,
-        blobstores: Arc<Mutex<Vec<Arc<Mutex<Blobstore>>>>>,
+        blobstores: Arc<Mutex<Vec<Arc<CoreBound<Blobstore>>>>>,
     ) -> Result<()> {
         let shutdown_fs = fs.clone();
         let shutdown_sig = shutdown.clone();
         let shutdown_poller_copy = shutdown_poller.clone();
 
-        // register a shutdown poller
-        // this is not a proper way to let user send shutdown signal
+        // register a shutdown poller, the signal is sent by
+        // `EngineOpts::finish` or `AppHandle`
         *shutdown_poller.lock().unwrap() = Poller::register(move || {
             if *shutdown_sig.lock().unwrap() {
                 info!("shutdown spdk environment");
-                shutdown_fs.lock().unwrap().unload_sync().unwrap();
+                let fs = &mut *shutdown_fs.lock().unwrap();
+                // blobfs may not be started, or failed to start
+                if !fs.ptr.is_null() {
+                    fs.unload_sync().unwrap();
+                }
                 shutdown_poller_copy.lock().unwrap().unregister();
                 app_stop();
             }
@@ -186,13 +226,18 @@ impl EngineOpts {
         })?;
 
         // initialize blobfs
+        if start_blobfs {
+            startup.send_replace(Startup::Starting(format!(
+                "blobfs on {}",
+                blobfs_bdev.unwrap()
+            )));
+        }
         if start_blobfs && !is_reload {
             let mut bdev = blob_bdev::BlobStoreBDev::create(blobfs_bdev.unwrap().as_str())?;
             let mut blobfs_opts = SpdkBlobfsOpts::init().await?;
             let blobfs = SpdkFilesystem::init(&mut bdev, &mut blobfs_opts).await?;
 
             *fs.lock().unwrap() = blobfs;
-            *fsflag.lock().unwrap() = true;
             info!("fs init success");
         } else if start_blobfs && is_reload {
             info!("before reload.....");
@@ -200,50 +245,71 @@ impl EngineOpts {
             let blobfs = SpdkFilesystem::load(&mut bdev).await?;
 
             *fs.lock().unwrap() = blobfs;
-            *fsflag.lock().unwrap() = true;
             info!("fs reload success");
         }
 
         // initialize blobstore on specific core
-        blobstore_bdev_list.into_iter().for_each(|opt| {
-            let bs_tmp = Arc::new(Mutex::new(Blobstore::default()));
-            let n = Arc::new(Notify::new());
-            let e = SpdkEvent::alloc(
-                opt.core,
-                build_blobstore as *const () as *mut c_void,
-                Box::into_raw(Box::new((
-                    CString::new(opt.bdev_name).expect("fail to parse bdev name"),
-                    bs_tmp.clone(),
-                    n.clone(),
-                    is_reload,
-                ))) as *mut c_void,
-            )
-            .unwrap();
-            e.call().unwrap();
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(async {
-                n.notified().await;
-            });
-            {
-                blobstores.clone().lock().unwrap().push(bs_tmp.clone());
-                *bsflag.lock().unwrap() = true;
-                if bs_tmp.lock().unwrap().ptr.is_null() {
-                    error!("push a none pointer");
-                } else {
-                    info!("create blobstore finish");
-                }
+        for opt in blobstore_bdev_list {
+            startup.send_replace(Startup::Starting(format!(
+                "blobstore on {} (core {})",
+                opt.bdev_name, opt.core
+            )));
+            let req = BuildBlobstore {
+                core: opt.core,
+                bdev: opt.bdev_name,
+                is_reload,
             };
-        });
+            let bs = CoreChannel::new(opt.core).call(req).await??;
+            blobstores.lock().unwrap().push(Arc::new(bs));
+            info!("create blobstore finish");
+        }
+        // ready only after every blobstore is pushed
+        startup.send_replace(Startup::Ready);
 
         Ok(())
     }
 
-    /// call ready after start spdk to wait for blobfs if needed
-    pub fn ready(&self) {
-        loop {
-            if *self.fsflag.lock().unwrap() && *self.bsflag.lock().unwrap() {
-                break;
+    /// mark the component being started as failed
+    fn fail_startup(startup: &watch::Sender<Startup>, reason: String) {
+        startup.send_modify(|s| {
+            if let Startup::Starting(component) = s {
+                *s = Startup::Failed(component.clone(), reason);
+            }
+        });
+    }
+
+    /// wait for blobfs and blobstores after start spdk
+    ///
+    /// returns which component failed to start and why, or which one is
+    /// still starting after `timeout`
+    pub async fn ready(&self, timeout: Duration) -> Result<()> {
+        let mut startup = self.startup.clone();
+        let wait = async {
+            loop {
+                let status = startup.borrow().clone();
+                match status {
+                    Startup::Ready => return Ok(()),
+                    Startup::Failed(component, reason) => {
+                        return Err(EngineError::StartFail(component, reason))
+                    }
+                    Startup::Starting(component) => {
+                        if startup.changed().await.is_err() {
+                            let reason = "SPDK app exits".to_string();
+                            return Err(EngineError::StartFail(component, reason));
+                        }
+                    }
+                }
             }
+        };
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(res) => res,
+            Err(_) => match self.startup.borrow().clone() {
+                Startup::Ready => Ok(()),
+                Startup::Failed(component, reason) => {
+                    Err(EngineError::StartFail(component, reason))
+                }
+                Startup::Starting(component) => Err(EngineError::StartTimeout(component)),
+            },
         }
     }
 
@@ -252,6 +318,32 @@ impl EngineOpts {
         *self.shutdown.lock().unwrap() = true;
     }
 
+    /// unload the blobstores started so far after startup timed out, each
+    /// gets `timeout` as its reactor may be the one stuck
+    pub async fn unload_started(&self, timeout: Duration) {
+        let list = self.blobstore_bdev_list.clone().unwrap_or_default();
+        for be in Self::started_bes(&list, &self.blobstores) {
+            if tokio::time::timeout(timeout, be.unload()).await.is_err() {
+                error!("timed out unloading blobstore {}", be.name);
+            }
+        }
+    }
+
+    /// send the shutdown signal and do not wait for the SPDK thread on
+    /// drop, for a startup that timed out and may never return
+    pub fn detach(&mut self) {
+        self.finish();
+        self.detached = true;
+    }
+
+    /// a handle to stop the SPDK env from elsewhere
+    pub fn app_handle(&self) -> AppHandle {
+        AppHandle {
+            shutdown: self.shutdown.clone(),
+            startup: self.startup.clone(),
+        }
+    }
+
     /// create a blob engine, for test
     pub fn create_be(&self) -> BlobEngine {
         let bs_lock = self.blobstores.lock().unwrap();
@@ -263,20 +355,322 @@ impl EngineOpts {
             bs_lock[0].clone(),
         )
     }
+
+    /// create one blob engine per blobstore, in configured order
+    pub fn create_bes(&self) -> Vec<BlobEngine> {
+        Self::started_bes(&self.blobstore_bdev_list.clone().unwrap(), &self.blobstores)
+    }
+
+    /// blob engines of the blobstores started so far, they are started in
+    /// configured order
+    fn started_bes(
+        bs_list: &[BsBindOpts],
+        blobstores: &Mutex<Vec<Arc<CoreBound<Blobstore>>>>,
+    ) -> Vec<BlobEngine> {
+        let bs_lock = blobstores.lock().unwrap();
+        bs_list
+            .iter()
+            .zip(bs_lock.iter())
+            .map(|(opt, bs)| BlobEngine::new(&opt.bdev_name, opt.core, 512, bs.clone()))
+            .collect()
+    }
 }
 
-fn build_blobstore(arg: *mut c_void) {
-    info!(">>>> build_blobstore is called");
-    let (bdev, bs, n, is_reload) =
-        unsafe { *Box::from_raw(arg as *mut (CString, Arc<Mutex<Blobstore>>, Arc<Notify>, bool)) };
-    let mut bs_dev =
-        blob_bdev::BlobStoreBDev::create(bdev.into_string().unwrap().as_str()).unwrap();
-    if !is_reload {
-        blob::Blobstore::init_sync(&mut bs_dev, Box::into_raw(Box::new((bs, n))) as *mut c_void)
-            .unwrap();
-    } else if is_reload {
-        blob::Blobstore::load_sync(&mut bs_dev, Box::into_raw(Box::new((bs, n))) as *mut c_void)
-            .unwrap();
-    }
-    info!("blob store initilize success");
+/// Stops the SPDK env started by `EngineOpts`, dropping it sends the
+/// shutdown signal without waiting
+pub struct AppHandle {
+    // Shutdown signal
+    shutdown: Arc<Mutex<bool>>,
+    // closed once the SPDK thread exits
+    startup: watch::Receiver<Startup>,
+}
+
+impl AppHandle {
+    /// unload blobfs, stop the app and wait for the SPDK thread to exit
+    pub async fn stop(mut self) {
+        *self.shutdown.lock().unwrap() = true;
+        while self.startup.changed().await.is_ok() {}
+    }
+}
+
+impl Drop for AppHandle {
+    fn drop(&mut self) {
+        *self.shutdown.lock().unwrap() = true;
+    }
+}
+
+/// Options to open a FileEngine
+pub struct FileEngineOpts {
+    // RocksDB path
+    pub(crate) db_path: String,
+    // SPDK configuration file
+    pub(crate) config_file: String,
+    // start reactor on which core
+    pub(crate) reactor_mask: String,
+    // start blobfs on which bdev
+    pub(crate) blobfs_bdev: String,
+    // blobstores holding chunk pages
+    pub(crate) blobstores: Vec<BsBindOpts>,
+    // App name
+    pub(crate) app_name: String,
+    pub(crate) cache_size_in_mb: u64,
+    // clusters of each thread blob
+    pub(crate) init_blob_size: u64,
+    // copies written for every page
+    pub(crate) replicas: usize,
+    // stripe geometry of chunks created afterwards, none for page mapping
+    pub(crate) erasure: Option<StripeGeometry>,
+    // memory budget of the page read cache, 0 to disable
+    pub(crate) page_cache_in_mb: u64,
+    // buffer small writes, flush threshold in bytes and flush interval
+    pub(crate) write_buffer: Option<(u64, Duration)>,
+    // acknowledge writes up to this size once journaled
+    pub(crate) deferred_apply: Option<u64>,
+    // page I/Os in flight in the engine
+    pub(crate) io_depth: usize,
+    // how long to wait for blobfs and blobstores to start
+    pub(crate) start_timeout: Duration,
+    // allocator workers
+    pub(crate) alloc_workers: usize,
+    // cores to pin allocator workers to, empty to not pin
+    pub(crate) alloc_cores: Vec<usize>,
+    // writes and size hints from this many bytes are large, 0 for one class
+    pub(crate) large_write: u64,
+    // growth policy of each allocation class
+    pub(crate) class_policy: HashMap<SizeClass, ClassPolicy>,
+    // compression algorithm for aligned write extents
+    pub(crate) compress_algo: CompressAlgo,
+    // checksum algorithm for newly created files
+    pub(crate) csum_algo: ChecksumAlgo,
+    // data key files, key version -> file holding 64 raw bytes
+    pub(crate) key_files: HashMap<u32, PathBuf>,
+    // key version to encrypt newly created files, none for plaintext
+    pub(crate) active_key: Option<u32>,
+}
+
+impl Default for FileEngineOpts {
+    fn default() -> Self {
+        Self {
+            db_path: String::new(),
+            config_file: String::new(),
+            reactor_mask: "0x1".to_string(),
+            blobfs_bdev: String::new(),
+            blobstores: vec![],
+            app_name: String::new(),
+            cache_size_in_mb: 0,
+            init_blob_size: 0,
+            replicas: 1,
+            erasure: None,
+            page_cache_in_mb: 0,
+            write_buffer: None,
+            deferred_apply: None,
+            io_depth: 64,
+            start_timeout: Duration::from_secs(60),
+            alloc_workers: NUM_THREAD,
+            alloc_cores: vec![],
+            large_write: 0,
+            class_policy: HashMap::new(),
+            compress_algo: CompressAlgo::default(),
+            csum_algo: ChecksumAlgo::default(),
+            key_files: HashMap::new(),
+            active_key: None,
+        }
+    }
+}
+
+impl FileEngineOpts {
+    /// set RocksDB path on blobfs
+    pub fn set_db_path(&mut self, path: &str) {
+        self.db_path = path.to_string();
+    }
+
+    /// set configuration file
+    pub fn set_config_file(&mut self, config: String) {
+        self.config_file = config;
+    }
+
+    /// set reactor mask to instruct which cores to start SPDK reactor
+    pub fn set_reactor_mask(&mut self, mask: &str) {
+        self.reactor_mask = mask.to_string();
+    }
+
+    /// set which bdev to build blobfs on
+    pub fn set_blobfs(&mut self, blobfs_bdev: &str) {
+        self.blobfs_bdev = blobfs_bdev.to_string();
+    }
+
+    /// set which bdev and core to run each blobstore
+    pub fn set_blobstore(&mut self, blobstore_bdev_list: Vec<BsBindOpts>) {
+        self.blobstores = blobstore_bdev_list;
+    }
+
+    /// set app name (optional)
+    pub fn set_name(&mut self, app_name: &str) {
+        self.app_name = app_name.to_string();
+    }
+
+    /// set RocksDB block cache size
+    pub fn set_cache_size(&mut self, cache_size_in_mb: u64) {
+        self.cache_size_in_mb = cache_size_in_mb;
+    }
+
+    /// set cluster count of each thread blob
+    pub fn set_blob_size(&mut self, init_blob_size: u64) {
+        self.init_blob_size = init_blob_size;
+    }
+
+    /// set how many copies of each page are written
+    ///
+    /// copies are put on different blobstores when more than one is
+    /// configured, a bad copy is repaired from a good one on read
+    pub fn set_replicas(&mut self, replicas: usize) {
+        self.replicas = replicas.max(1);
+    }
+
+    /// stripe chunks created afterwards with Reed-Solomon `data_shards` +
+    /// `parity_shards` coding
+    ///
+    /// shards of a stripe are spread over the blobstores, any
+    /// `parity_shards` of them can be lost, both counts must be positive
+    pub fn set_erasure(&mut self, data_shards: usize, parity_shards: usize) -> Result<()> {
+        if data_shards == 0 || parity_shards == 0 {
+            return Err(EngineError::ErasureErr(format!(
+                "a stripe needs data and parity shards, got {} + {}",
+                data_shards, parity_shards
+            )));
+        }
+        self.erasure = Some(StripeGeometry {
+            data_shards,
+            parity_shards,
+        });
+        Ok(())
+    }
+
+    /// set memory budget of the cache of verified pages, 0 to disable
+    pub fn set_page_cache(&mut self, page_cache_in_mb: u64) {
+        self.page_cache_in_mb = page_cache_in_mb;
+    }
+
+    /// buffer unaligned writes per chunk, merged writes are flushed once
+    /// `threshold` bytes are buffered, after `interval`, or on `fsync`
+    ///
+    /// buffered writes are journaled in RocksDB before a write returns
+    pub fn set_write_buffer(&mut self, threshold: u64, interval: Duration) {
+        self.write_buffer = Some((threshold, interval));
+    }
+
+    /// acknowledge writes up to `small_write` bytes, and unaligned writes,
+    /// once they are journaled in RocksDB
+    ///
+    /// a background flusher applies them to blobs and then deletes the
+    /// journal records, reads see pending writes
+    pub fn set_deferred_apply(&mut self, small_write: u64) {
+        self.deferred_apply = Some(small_write);
+    }
+
+    /// set how many page I/Os are in flight at once, the limit is shared by
+    /// every read and write of the engine
+    pub fn set_io_depth(&mut self, io_depth: usize) {
+        self.io_depth = io_depth.max(1);
+    }
+
+    /// set how long to wait for blobfs and blobstores to start
+    pub fn set_start_timeout(&mut self, timeout: Duration) {
+        self.start_timeout = timeout;
+    }
+
+    /// set how many allocator workers allocate pages
+    ///
+    /// each worker owns one blob of every blob group, groups get more blobs
+    /// on reload if there are more workers than before
+    pub fn set_alloc_workers(&mut self, workers: usize) {
+        self.alloc_workers = workers.max(1);
+    }
+
+    /// pin allocator worker i to `cores[i % cores.len()]`
+    pub fn set_alloc_cores(&mut self, cores: Vec<usize>) {
+        self.alloc_cores = cores;
+    }
+
+    /// put pages of writes of at least `bytes`, and of chunks whose size
+    /// hint is at least `bytes`, on large class blobs, 0 for one class
+    ///
+    /// blobs created on open are small ones, the large class only gets
+    /// blobs by growing, see `set_class_growth`
+    pub fn set_large_write(&mut self, bytes: u64) {
+        self.large_write = bytes;
+    }
+
+    /// let `class` add blobs of `blob_size` clusters when it runs out of
+    /// free pages, up to `max_groups` groups
+    ///
+    /// a class without space takes pages from other classes
+    pub fn set_class_growth(&mut self, class: SizeClass, blob_size: u64, max_groups: usize) {
+        self.class_policy.insert(
+            class,
+            ClassPolicy {
+                blob_size,
+                max_groups,
+            },
+        );
+    }
+
+    /// set compression algorithm for fully covered pages of writes
+    pub fn set_compress_algo(&mut self, algo: CompressAlgo) {
+        self.compress_algo = algo;
+    }
+
+    /// set checksum algorithm used by files created without one
+    ///
+    /// existing files keep the algorithm they were created with
+    pub fn set_checksum_algo(&mut self, algo: ChecksumAlgo) {
+        self.csum_algo = algo;
+    }
+
+    /// load data key `version` from a local key file holding 64 raw bytes
+    /// on open
+    ///
+    /// keep old versions as long as files encrypted with them exist
+    pub fn add_key_file(&mut self, version: u32, key_file: impl AsRef<Path>) {
+        self.key_files
+            .insert(version, key_file.as_ref().to_path_buf());
+    }
+
+    /// encrypt files created afterwards with a key added by `add_key_file`,
+    /// none for plaintext
+    ///
+    /// existing files keep the key version they were created with
+    pub fn set_active_key(&mut self, version: Option<u32>) {
+        self.active_key = version;
+    }
+}
+
+/// start a blobstore on the core serving the request
+struct BuildBlobstore {
+    core: u32,
+    bdev: String,
+    is_reload: bool,
+}
+
+impl Request for BuildBlobstore {
+    type Response = Result<CoreBound<Blobstore>>;
+
+    fn serve(self) -> Pin<Box<dyn Future<Output = Self::Response>>> {
+        Box::pin(async move {
+            info!(">>>> build_blobstore is called");
+            let mut bs_dev = blob_bdev::BlobStoreBDev::create(&self.bdev)?;
+            let bs = if !self.is_reload {
+                blob::Blobstore::init(&mut bs_dev).await?
+            } else {
+                blob::Blobstore::load(&mut bs_dev).await?
+            };
+            if bs.ptr.is_null() {
+                return Err(EngineError::BsInitError);
+            }
+            info!("blob store initilize success");
+            // SAFETY: a blobstore handle is a plain pointer, dropping it
+            // does not call into SPDK, it is unloaded by `Op::Unload`
+            Ok(unsafe { CoreBound::new(self.core, bs) })
+        })
+    }
 }
@@ -0,0 +1,97 @@
+//! This module queues bad page copies found by reads for rewriting
+//!
+//! A read returns the data of the first good copy right away, the bytes
+//! each bad copy should hold are kept here until a background task writes
+//! them, a position freed in the meantime may be taken by another page so
+//! its repair is dropped
+
+use crate::BlobRef;
+use std::{collections::HashMap, sync::Mutex};
+
+#[derive(Default)]
+pub struct PageRepairs {
+    // (blob, offset) -> stored bytes of the position
+    pending: Mutex<HashMap<(BlobRef, u64), Vec<u8>>>,
+}
+
+impl PageRepairs {
+    /// queue the bytes to store at a bad copy, a later read of the same
+    /// position replaces them
+    pub fn queue(&self, blob: BlobRef, offset: u64, data: Vec<u8>) {
+        self.pending.lock().unwrap().insert((blob, offset), data);
+    }
+
+    /// drop the repairs of freed positions
+    pub fn cancel(&self, places: impl IntoIterator<Item = (BlobRef, u64)>) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.is_empty() {
+            return;
+        }
+        for place in places {
+            pending.remove(&place);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.lock().unwrap().is_empty()
+    }
+
+    /// take every queued repair
+    pub fn take(&self) -> HashMap<(BlobRef, u64), Vec<u8>> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Hasher;
+    use async_spdk::blob::BlobId;
+
+    #[test]
+    pub fn test_corrupt_copy() {
+        let hasher = Hasher::new();
+        let primary = BlobRef {
+            bs: 0,
+            bid: BlobId::default(),
+        };
+        let copy = BlobRef {
+            bs: 1,
+            bid: BlobId::default(),
+        };
+        let page = vec![7u8; 4096];
+        let csum = hasher.checksum(&page);
+        let mut disk = HashMap::new();
+        disk.insert((primary, 0), page.clone());
+        disk.insert((copy, 8), page.clone());
+        // the primary copy goes bad, a read is served by the mirror
+        disk.get_mut(&(primary, 0)).unwrap()[100] ^= 0xff;
+
+        let repairs = PageRepairs::default();
+        let mut good = None;
+        for place in [(primary, 0), (copy, 8)] {
+            if hasher.checksum(&disk[&place]) == csum {
+                good = Some(disk[&place].clone());
+                break;
+            }
+            repairs.queue(place.0, place.1, page.clone());
+        }
+        assert_eq!(good.unwrap(), page);
+        assert!(!repairs.is_empty());
+
+        // freeing other positions keeps the repair
+        repairs.cancel([(primary, 8), (copy, 0)]);
+        let pending = repairs.take();
+        assert_eq!(pending.len(), 1);
+        for (place, data) in pending {
+            disk.insert(place, data);
+        }
+        assert_eq!(hasher.checksum(&disk[&(primary, 0)]), csum);
+        assert!(repairs.is_empty());
+
+        // a freed position may be reused, its repair is dropped
+        repairs.queue(primary, 0, page.clone());
+        repairs.cancel([(primary, 0), (copy, 8)]);
+        assert!(repairs.is_empty());
+    }
+}
@@ -0,0 +1,37 @@
+//! This module turns SIGINT and SIGTERM into an orderly shutdown
+//!
+//! SPDK's own handlers stop the app without unloading blobstores, tokio
+//! chains to the handler it finds installed, so SPDK's are reset first
+
+use crate::error::{EngineError, Result};
+use std::{future::Future, io};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// handle SIGINT and SIGTERM, the returned future resolves on the first
+/// one and the process aborts on the second
+///
+/// call it in a tokio runtime after SPDK is started, otherwise SPDK replaces
+/// the handlers
+pub fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+    for sig in [libc::SIGINT, libc::SIGTERM] {
+        if unsafe { libc::signal(sig, libc::SIG_DFL) } == libc::SIG_ERR {
+            return Err(EngineError::SignalErr(io::Error::last_os_error()));
+        }
+    }
+    let mut interrupt = signal(SignalKind::interrupt()).map_err(EngineError::SignalErr)?;
+    let mut terminate = signal(SignalKind::terminate()).map_err(EngineError::SignalErr)?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        // a second signal gives up the orderly shutdown
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+            std::process::abort();
+        });
+    })
+}
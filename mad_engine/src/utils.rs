@@ -1,15 +1,26 @@
 //! This module includes some self-implemented components
 //!
-//! include: bitmap, hasher
+//! include: bitmap, hasher, compressor, AES-XTS cipher, erasure code, page cache
 
+use crate::error::{EngineError, Result};
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes256;
 use crc::{Crc, CRC_32_ISO_HDLC};
+use lru::LruCache;
+use reed_solomon_erasure::galois_8::ReedSolomon;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher as _};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use twox_hash::XxHash64;
 
 /// word size in bitmap
 const WORD_SIZE: u64 = 64;
 /// mask used to do some ceiling
 const WORD_MASK: u64 = 63;
-/// temporary number of thread, should be equal to #cpu_num?
+/// default number of allocator workers
 pub const NUM_THREAD: usize = 4;
 /// blob size in MB
 pub const BLOB_SIZE: u64 = 64;
@@ -20,49 +31,373 @@ pub const CLUSTER_SIZE: u64 = 256 * 8;
 
 pub const MAGIC: &str = "MadEngine";
 
+/// checksum algorithm of a chunk's pages
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgo {
+    /// no checksum, always zero
+    #[serde(rename = "none")]
+    None,
+    /// CRC-32 (ISO-HDLC)
+    #[default]
+    #[serde(rename = "crc32", alias = "CRC32")]
+    Crc32,
+    /// CRC-32C (Castagnoli), use SSE4.2 / ARMv8 CRC instructions if present
+    #[serde(rename = "crc32c")]
+    Crc32c,
+    /// 64-bit xxHash with zero seed
+    #[serde(rename = "xxhash64")]
+    XxHash64,
+}
+
 pub struct Hasher {
+    algo: ChecksumAlgo,
     ck_sum: Crc<u32>,
 }
 
 impl Hasher {
+    /// CRC-32 hasher, also used to derive global metadata key
     pub fn new() -> Self {
+        Self::with_algo(ChecksumAlgo::Crc32)
+    }
+
+    pub fn with_algo(algo: ChecksumAlgo) -> Self {
         Self {
+            algo,
             ck_sum: Crc::<u32>::new(&CRC_32_ISO_HDLC),
         }
     }
 
-    pub fn checksum(&self, data: &[u8]) -> u32 {
-        self.ck_sum.checksum(data)
+    pub fn checksum(&self, data: &[u8]) -> u64 {
+        match self.algo {
+            ChecksumAlgo::None => 0,
+            ChecksumAlgo::Crc32 => self.ck_sum.checksum(data) as u64,
+            ChecksumAlgo::Crc32c => crc32c::crc32c(data) as u64,
+            ChecksumAlgo::XxHash64 => {
+                let mut h = XxHash64::with_seed(0);
+                h.write(data);
+                h.finish()
+            }
+        }
+    }
+}
+
+/// compression algorithm of write extents
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressAlgo {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "lz4")]
+    Lz4,
+    #[serde(rename = "zstd")]
+    Zstd,
+}
+
+/// zstd compression level
+const ZSTD_LEVEL: i32 = 3;
+
+/// compress data, return none if compression is disabled or fails
+pub fn compress(algo: CompressAlgo, data: &[u8]) -> Option<Vec<u8>> {
+    match algo {
+        CompressAlgo::None => None,
+        CompressAlgo::Lz4 => Some(lz4_flex::compress(data)),
+        CompressAlgo::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+    }
+}
+
+/// decompress data into exactly `raw_len` bytes
+pub fn decompress(algo: CompressAlgo, data: &[u8], raw_len: usize) -> Result<Vec<u8>> {
+    let raw = match algo {
+        CompressAlgo::None => Some(data.to_vec()),
+        CompressAlgo::Lz4 => lz4_flex::decompress(data, raw_len).ok(),
+        CompressAlgo::Zstd => zstd::bulk::decompress(data, raw_len).ok(),
+    };
+    match raw {
+        Some(raw) if raw.len() == raw_len => Ok(raw),
+        _ => Err(EngineError::DecompressErr),
+    }
+}
+
+/// AES-XTS key size, two AES-256 keys
+pub const XTS_KEY_SIZE: usize = 64;
+/// AES block size
+const AES_BLOCK_SIZE: usize = 16;
+
+/// AES-256-XTS (IEEE 1619) on data units of whole AES blocks
+pub struct Xts {
+    data_cipher: Aes256,
+    tweak_cipher: Aes256,
+}
+
+impl Xts {
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.len() != XTS_KEY_SIZE {
+            return Err(EngineError::KeyErr(format!(
+                "expect {} key bytes, get {}",
+                XTS_KEY_SIZE,
+                key.len()
+            )));
+        }
+        // equal halves break the XTS security bound (IEEE 1619-2018 5.1)
+        if key[..XTS_KEY_SIZE / 2] == key[XTS_KEY_SIZE / 2..] {
+            return Err(EngineError::KeyErr(
+                "the two halves of an XTS key are equal".to_string(),
+            ));
+        }
+        Ok(Self {
+            data_cipher: Aes256::new(GenericArray::from_slice(&key[..XTS_KEY_SIZE / 2])),
+            tweak_cipher: Aes256::new(GenericArray::from_slice(&key[XTS_KEY_SIZE / 2..])),
+        })
+    }
+
+    /// encrypt one data unit in place
+    pub fn encrypt(&self, tweak: u128, data: &mut [u8]) {
+        self.process(tweak, data, true);
+    }
+
+    /// decrypt one data unit in place
+    pub fn decrypt(&self, tweak: u128, data: &mut [u8]) {
+        self.process(tweak, data, false);
+    }
+
+    fn process(&self, tweak: u128, data: &mut [u8], encrypt: bool) {
+        assert_eq!(data.len() % AES_BLOCK_SIZE, 0);
+        let mut t = GenericArray::from(tweak.to_le_bytes());
+        self.tweak_cipher.encrypt_block(&mut t);
+        for block in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+            block.iter_mut().zip(t.iter()).for_each(|(b, t)| *b ^= t);
+            let b = GenericArray::from_mut_slice(block);
+            if encrypt {
+                self.data_cipher.encrypt_block(b);
+            } else {
+                self.data_cipher.decrypt_block(b);
+            }
+            block.iter_mut().zip(t.iter()).for_each(|(b, t)| *b ^= t);
+            // multiply tweak by x in GF(2^128)
+            let mut carry = 0;
+            for byte in t.iter_mut() {
+                let next = *byte >> 7;
+                *byte = (*byte << 1) | carry;
+                carry = next;
+            }
+            if carry != 0 {
+                t[0] ^= 0x87;
+            }
+        }
+    }
+}
+
+/// Reed-Solomon stripe geometry, `data_shards` pages protected by
+/// `parity_shards` parity pages
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct StripeGeometry {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+}
+
+impl StripeGeometry {
+    pub fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+}
+
+/// Reed-Solomon code over GF(2^8)
+pub struct ErasureCode {
+    rs: ReedSolomon,
+    geo: StripeGeometry,
+}
+
+impl ErasureCode {
+    pub fn new(geo: StripeGeometry) -> Result<Self> {
+        let rs = ReedSolomon::new(geo.data_shards, geo.parity_shards)
+            .map_err(|e| EngineError::ErasureErr(format!("{:?}", e)))?;
+        Ok(Self { rs, geo })
+    }
+
+    /// split `data` into data shards and append parity shards
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        assert_eq!(data.len() % self.geo.data_shards, 0);
+        let shard_len = data.len() / self.geo.data_shards;
+        let mut shards: Vec<Vec<u8>> = data.chunks(shard_len).map(|c| c.to_vec()).collect();
+        shards.resize(self.geo.total_shards(), vec![0u8; shard_len]);
+        self.rs
+            .encode(&mut shards)
+            .map_err(|e| EngineError::ErasureErr(format!("{:?}", e)))?;
+        Ok(shards)
+    }
+
+    /// fill in missing shards, at most `parity_shards` may be missing
+    pub fn reconstruct(&self, shards: &mut [Option<Vec<u8>>]) -> Result<()> {
+        self.rs
+            .reconstruct(shards)
+            .map_err(|e| EngineError::ErasureErr(format!("{:?}", e)))
+    }
+}
+
+/// number of shards in page cache
+const CACHE_SHARDS: usize = 16;
+
+/// hit/miss statistics of page cache
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // cached pages
+    pub pages: u64,
+    // most pages the cache can hold
+    pub capacity: u64,
+}
+
+/// sharded LRU cache of verified pages
+pub struct PageCache<K: Hash + Eq> {
+    shards: Vec<Mutex<LruCache<K, Vec<u8>>>>,
+    capacity: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl<K: Hash + Eq> PageCache<K> {
+    /// cache at most `budget` bytes of `page_size` pages
+    pub fn new(budget: u64, page_size: u64) -> Self {
+        let per_shard = (budget / page_size / CACHE_SHARDS as u64).max(1) as usize;
+        Self {
+            shards: (0..CACHE_SHARDS)
+                .map(|_| Mutex::new(LruCache::new(NonZeroUsize::new(per_shard).unwrap())))
+                .collect(),
+            capacity: (per_shard * CACHE_SHARDS) as u64,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<LruCache<K, Vec<u8>>> {
+        let mut h = DefaultHasher::new();
+        key.hash(&mut h);
+        &self.shards[h.finish() as usize % CACHE_SHARDS]
+    }
+
+    /// copy a cached page into `out`, return false on miss
+    pub fn get(&self, key: &K, out: &mut [u8]) -> bool {
+        match self.shard(key).lock().unwrap().get(key) {
+            Some(page) => {
+                out.copy_from_slice(page);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    pub fn insert(&self, key: K, page: &[u8]) {
+        let mut shard = self.shard(&key).lock().unwrap();
+        if let Some((k, _)) = shard.push(key, page.to_vec()) {
+            if !shard.contains(&k) {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn invalidate(&self, key: &K) {
+        self.shard(key).lock().unwrap().pop(key);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            pages: self
+                .shards
+                .iter()
+                .map(|s| s.lock().unwrap().len() as u64)
+                .sum(),
+            capacity: self.capacity,
+        }
     }
 }
 
+/// Bitmap of pages, a set bit is a used page
+///
+/// A summary level marks full words so free bits are found without
+/// scanning full words, padding bits past `count` are always set
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "BitMapRepr")]
 pub struct BitMap {
     count: u64,
     words: Vec<u64>,
+    // bit i is set if words[i] is full, padding bits are set
+    #[serde(skip_serializing)]
+    full: Vec<u64>,
+    // set bits below count
+    #[serde(skip_serializing)]
+    ones: u64,
+}
+
+// only the words are stored, the summary is rebuilt when loaded
+#[derive(Deserialize)]
+struct BitMapRepr {
+    count: u64,
+    words: Vec<u64>,
+}
+
+impl From<BitMapRepr> for BitMap {
+    fn from(r: BitMapRepr) -> Self {
+        Self::with_words(r.count, r.words)
+    }
 }
 
 impl BitMap {
     /// create a bitmap with capacity, set all bits to zero
     pub fn new(count: u64) -> Self {
-        let word_count = (count + WORD_SIZE - 1) / WORD_SIZE;
-        let words = vec![0; word_count as usize];
-        Self { count, words }
+        let word_count = count.div_ceil(WORD_SIZE);
+        Self::with_words(count, vec![0; word_count as usize])
     }
 
     /// create a bitmap with capacity, set all bits to one
     ///
     /// basically for recycling
     pub fn new_set_ones(count: u64) -> Self {
-        let word_count = (count + WORD_SIZE - 1) / WORD_SIZE;
-        let words = vec![u64::MAX; word_count as usize];
-        Self { count, words }
+        let word_count = count.div_ceil(WORD_SIZE);
+        Self::with_words(count, vec![u64::MAX; word_count as usize])
+    }
+
+    /// set padding bits and build the summary
+    fn with_words(count: u64, mut words: Vec<u64>) -> Self {
+        words.resize(count.div_ceil(WORD_SIZE) as usize, 0);
+        if count & WORD_MASK != 0 {
+            *words.last_mut().unwrap() |= u64::MAX << (count & WORD_MASK);
+        }
+        let padding = words.len() as u64 * WORD_SIZE - count;
+        let ones = words.iter().map(|w| w.count_ones() as u64).sum::<u64>() - padding;
+        let mut full = vec![u64::MAX; (words.len() + WORD_MASK as usize) / WORD_SIZE as usize];
+        for (i, word) in words.iter().enumerate() {
+            if *word != u64::MAX {
+                full[i / WORD_SIZE as usize] &= !(1u64 << (i as u64 & WORD_MASK));
+            }
+        }
+        Self {
+            count,
+            words,
+            full,
+            ones,
+        }
     }
 
     pub fn get_size(&self) -> u64 {
         self.count
     }
 
+    /// number of unset bits
+    pub fn free_count(&self) -> u64 {
+        self.count - self.ones
+    }
+
     /// parse an index to specific location
     fn parse_index(index: u64) -> (u64, u64) {
         let word_index = index / WORD_SIZE;
@@ -73,35 +408,117 @@ impl BitMap {
     /// check index is set or not
     pub fn get(&self, index: u64) -> bool {
         let (word_index, word_bit_index) = Self::parse_index(index);
-        if self.words[word_index as usize] >> word_bit_index & 1 == 0 {
-            return false;
-        }
-        true
+        self.words[word_index as usize] >> word_bit_index & 1 == 1
     }
 
-    /// set one bit
+    /// set one bit, return false if it is already set
     pub fn set(&mut self, index: u64) -> bool {
-        let (word_index, word_bit_index) = Self::parse_index(index);
-        self.words[word_index as usize] |= (1 << word_bit_index) as u64;
-        true
+        self.set_range(index, 1) == 1
     }
 
-    /// clear one bit
+    /// clear one bit, return false if it is already clear
     pub fn clear(&mut self, index: u64) -> bool {
-        let (word_index, word_bit_index) = Self::parse_index(index);
-        self.words[word_index as usize] &= !(1 << word_bit_index) as u64;
-        true
+        self.clear_range(index, 1) == 1
+    }
+
+    /// set bits [start, start + n), return how many bits are changed
+    pub fn set_range(&mut self, start: u64, n: u64) -> u64 {
+        self.update_range(start, n, true)
+    }
+
+    /// clear bits [start, start + n), return how many bits are changed
+    pub fn clear_range(&mut self, start: u64, n: u64) -> u64 {
+        self.update_range(start, n, false)
+    }
+
+    fn update_range(&mut self, start: u64, n: u64, set: bool) -> u64 {
+        assert!(start + n <= self.count, "bit out of range");
+        let mut changed = 0;
+        let mut index = start;
+        while index < start + n {
+            let (word_index, word_bit_index) = Self::parse_index(index);
+            let len = (WORD_SIZE - word_bit_index).min(start + n - index);
+            let mask = (u64::MAX >> (WORD_SIZE - len)) << word_bit_index;
+            let old = self.words[word_index as usize];
+            let new = if set { old | mask } else { old & !mask };
+            changed += (old ^ new).count_ones() as u64;
+            self.words[word_index as usize] = new;
+            let (s, bit) = Self::parse_index(word_index);
+            if new == u64::MAX {
+                self.full[s as usize] |= 1 << bit;
+            } else {
+                self.full[s as usize] &= !(1 << bit);
+            }
+            index += len;
+        }
+        if set {
+            self.ones += changed;
+        } else {
+            self.ones -= changed;
+        }
+        changed
+    }
+
+    /// first word at or after `from` with an unset bit
+    fn next_free_word(&self, from: u64) -> Option<u64> {
+        let (mut s, bit) = Self::parse_index(from);
+        let mut avail = !*self.full.get(s as usize)? & (u64::MAX << bit);
+        loop {
+            if avail != 0 {
+                return Some(s * WORD_SIZE + avail.trailing_zeros() as u64);
+            }
+            s += 1;
+            avail = !*self.full.get(s as usize)?;
+        }
     }
 
     /// find first unset bit, return none if none
     ///
     /// note that always less significant first
     pub fn find(&self) -> Option<u64> {
-        for (idx, word) in self.words.iter().enumerate() {
-            if *word != u64::MAX {
-                let bit_idx = word.trailing_ones() as u64;
-                return Some(idx as u64 * WORD_SIZE + bit_idx);
+        let word_index = self.next_free_word(0)?;
+        let bit_idx = self.words[word_index as usize].trailing_ones() as u64;
+        Some(word_index * WORD_SIZE + bit_idx)
+    }
+
+    /// find the first run of `n` unset bits, return its start
+    pub fn find_range(&self, n: u64) -> Option<u64> {
+        if n == 0 || n > self.free_count() {
+            return None;
+        }
+        let (mut run_start, mut run_len) = (0, 0);
+        let mut word_index = 0;
+        while word_index < self.words.len() as u64 {
+            if self.words[word_index as usize] == u64::MAX {
+                // a full word ends the run
+                run_len = 0;
+                word_index = self.next_free_word(word_index)?;
+            }
+            let word = self.words[word_index as usize];
+            if word == 0 {
+                if run_len == 0 {
+                    run_start = word_index * WORD_SIZE;
+                }
+                run_len += WORD_SIZE;
+            } else {
+                for bit in 0..WORD_SIZE {
+                    if word >> bit & 1 == 1 {
+                        run_len = 0;
+                        continue;
+                    }
+                    if run_len == 0 {
+                        run_start = word_index * WORD_SIZE + bit;
+                    }
+                    run_len += 1;
+                    if run_len >= n {
+                        return Some(run_start);
+                    }
+                }
             }
+            if run_len >= n {
+                return Some(run_start);
+            }
+            word_index += 1;
         }
         None
     }
@@ -116,4 +533,146 @@ mod test {
         assert_eq!(0xCBF43926, h.checksum(b"123456789"));
         assert_eq!(0x3DCA6FAD, h.checksum(b"this is a hasher test"));
     }
+
+    #[test]
+    pub fn test_checksum_algo() {
+        assert_eq!(
+            0,
+            Hasher::with_algo(ChecksumAlgo::None).checksum(b"123456789")
+        );
+        assert_eq!(
+            0xE3069283,
+            Hasher::with_algo(ChecksumAlgo::Crc32c).checksum(b"123456789")
+        );
+        assert_eq!(
+            0xEF46DB3751D8E999,
+            Hasher::with_algo(ChecksumAlgo::XxHash64).checksum(b"")
+        );
+        let algo: ChecksumAlgo = serde_json::from_str("\"CRC32\"").unwrap();
+        assert_eq!(ChecksumAlgo::Crc32, algo);
+    }
+
+    #[test]
+    pub fn test_xts() {
+        let key: Vec<u8> = (0..XTS_KEY_SIZE as u8).collect();
+        let xts = Xts::new(&key).unwrap();
+        let data: Vec<u8> = (0..512).map(|i| (i * 7 % 256) as u8).collect();
+        let mut buf = data.clone();
+        xts.encrypt(5, &mut buf);
+        assert_eq!(
+            [
+                0x35, 0xaf, 0x68, 0x69, 0x01, 0xa5, 0xcb, 0x6d, 0xf9, 0xc1, 0x80, 0xd9, 0x92, 0x26,
+                0xdf, 0xef
+            ],
+            buf[..16]
+        );
+        assert_eq!(
+            [
+                0xad, 0x77, 0xd4, 0xe6, 0x33, 0xd7, 0x77, 0x47, 0xe0, 0x39, 0x06, 0x65, 0xf9, 0xc0,
+                0x52, 0x26
+            ],
+            buf[496..]
+        );
+        xts.decrypt(5, &mut buf);
+        assert_eq!(data, buf);
+        assert!(Xts::new(&key[1..]).is_err());
+        assert!(Xts::new(&[7u8; XTS_KEY_SIZE]).is_err());
+    }
+
+    #[test]
+    pub fn test_erasure_code() {
+        let geo = StripeGeometry {
+            data_shards: 4,
+            parity_shards: 2,
+        };
+        let code = ErasureCode::new(geo).unwrap();
+        let data: Vec<u8> = (0..2048).map(|i| (i * 13 % 251) as u8).collect();
+        let shards = code.encode(&data).unwrap();
+        assert_eq!(6, shards.len());
+        assert_eq!(data, shards[..4].concat());
+
+        let mut lost: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+        lost[1] = None;
+        lost[4] = None;
+        code.reconstruct(&mut lost).unwrap();
+        let lost: Vec<Vec<u8>> = lost.into_iter().map(|s| s.unwrap()).collect();
+        assert_eq!(shards, lost);
+
+        let mut lost: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        lost[0] = None;
+        lost[2] = None;
+        lost[5] = None;
+        assert!(code.reconstruct(&mut lost).is_err());
+    }
+
+    #[test]
+    pub fn test_page_cache() {
+        // one page per shard
+        let cache: PageCache<u64> = PageCache::new(16 * 512, 512);
+        let mut out = vec![0u8; 512];
+        assert!(!cache.get(&1, &mut out));
+        cache.insert(1, &[7u8; 512]);
+        assert!(cache.get(&1, &mut out));
+        assert_eq!(vec![7u8; 512], out);
+        cache.insert(1, &[8u8; 512]);
+        assert!(cache.get(&1, &mut out));
+        assert_eq!(vec![8u8; 512], out);
+        cache.invalidate(&1);
+        assert!(!cache.get(&1, &mut out));
+        for key in 0..64 {
+            cache.insert(key, &[0u8; 512]);
+        }
+        let stats = cache.stats();
+        assert_eq!(2, stats.hits);
+        assert_eq!(2, stats.misses);
+        assert_eq!(16, stats.capacity);
+        assert_eq!(16, stats.pages);
+        assert_eq!(48, stats.evictions);
+    }
+
+    #[test]
+    pub fn test_compress() {
+        let data = vec![7u8; 4096];
+        assert!(compress(CompressAlgo::None, &data).is_none());
+        for algo in [CompressAlgo::Lz4, CompressAlgo::Zstd] {
+            let c = compress(algo, &data).unwrap();
+            assert!(c.len() < data.len());
+            assert_eq!(data, decompress(algo, &c, data.len()).unwrap());
+            assert!(decompress(algo, &c, data.len() - 1).is_err());
+        }
+    }
+
+    #[test]
+    pub fn test_bitmap() {
+        let mut bm = BitMap::new(200);
+        assert_eq!(200, bm.free_count());
+        assert!(bm.set(40));
+        assert!(!bm.set(40));
+        assert!(bm.get(40));
+        assert_eq!(Some(0), bm.find());
+        assert_eq!(63, bm.set_range(0, 64));
+        assert_eq!(Some(64), bm.find());
+        // bits past the count are never free
+        assert_eq!(Some(64), bm.find_range(136));
+        assert_eq!(None, bm.find_range(137));
+        assert_eq!(100, bm.set_range(100, 100));
+        assert_eq!(Some(64), bm.find_range(36));
+        assert_eq!(None, bm.find_range(37));
+        assert!(bm.clear(150));
+        assert_eq!(36, bm.set_range(64, 36));
+        assert_eq!(Some(150), bm.find_range(1));
+        assert_eq!(Some(150), bm.find());
+        assert_eq!(1, bm.free_count());
+
+        let bm: BitMap = serde_json::from_str(&serde_json::to_string(&bm).unwrap()).unwrap();
+        assert_eq!(1, bm.free_count());
+        assert_eq!(Some(150), bm.find());
+
+        let mut bm = BitMap::new_set_ones(4096 * 2);
+        assert_eq!(None, bm.find());
+        assert_eq!(64, bm.clear_range(5000, 64));
+        assert_eq!(Some(5000), bm.find());
+        assert_eq!(Some(5000), bm.find_range(64));
+        assert_eq!(None, bm.find_range(65));
+    }
 }
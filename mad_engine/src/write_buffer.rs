@@ -0,0 +1,400 @@
+//! This module keeps small writes in memory until they are flushed
+//!
+//! Every buffered write is journaled in RocksDB first, so it survives a crash
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// number of flush mutexes
+const FLUSH_STRIPES: usize = 64;
+
+/// a journaled write, replayed on reload if it was not flushed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalRecord {
+    pub(crate) offset: u64,
+    #[serde(with = "serde_bytes")]
+    pub(crate) data: Vec<u8>,
+}
+
+/// buffered writes of one chunk
+#[derive(Debug, Clone)]
+pub struct ChunkBuffer {
+    // offset -> data, ranges neither overlap nor touch
+    pub(crate) ranges: BTreeMap<u64, Vec<u8>>,
+    // journal records holding the buffered data
+    pub(crate) seqs: Vec<u64>,
+    // when the oldest buffered write arrived
+    pub(crate) since: Instant,
+}
+
+impl ChunkBuffer {
+    fn new() -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+            seqs: vec![],
+            since: Instant::now(),
+        }
+    }
+
+    /// merge a write with the ranges it overlaps or touches
+    fn insert(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        let touched: Vec<u64> = self
+            .ranges
+            .range(..=end)
+            .filter(|(start, d)| **start + d.len() as u64 >= offset)
+            .map(|(start, _)| *start)
+            .collect();
+        let mut start = offset;
+        let mut stop = end;
+        for s in touched.iter() {
+            start = start.min(*s);
+            stop = stop.max(*s + self.ranges[s].len() as u64);
+        }
+        let mut merged = vec![0u8; (stop - start) as usize];
+        for s in touched {
+            let d = self.ranges.remove(&s).unwrap();
+            merged[(s - start) as usize..(s - start) as usize + d.len()].copy_from_slice(&d);
+        }
+        merged[(offset - start) as usize..(end - start) as usize].copy_from_slice(data);
+        self.ranges.insert(start, merged);
+    }
+
+    /// drop bytes `flushed` has written that no later write changed, the
+    /// rest is split into ranges around them
+    fn trim(&mut self, flushed: &ChunkBuffer) {
+        for (start, data) in std::mem::take(&mut self.ranges) {
+            let end = start + data.len() as u64;
+            let mut stale = vec![false; data.len()];
+            for (s, d) in flushed.ranges.range(..end) {
+                let e = s + d.len() as u64;
+                if e <= start {
+                    continue;
+                }
+                let from = start.max(*s);
+                let to = end.min(e);
+                for i in from..to {
+                    stale[(i - start) as usize] = data[(i - start) as usize] == d[(i - s) as usize];
+                }
+            }
+            let mut i = 0;
+            while i < data.len() {
+                if stale[i] {
+                    i += 1;
+                    continue;
+                }
+                let j = (i..data.len()).find(|j| stale[*j]).unwrap_or(data.len());
+                self.ranges.insert(start + i as u64, data[i..j].to_vec());
+                i = j;
+            }
+        }
+    }
+
+    /// buffered bytes
+    pub(crate) fn bytes(&self) -> u64 {
+        self.ranges.values().map(|d| d.len() as u64).sum()
+    }
+
+    /// end of the last buffered range
+    pub(crate) fn end(&self) -> u64 {
+        self.ranges
+            .iter()
+            .next_back()
+            .map_or(0, |(s, d)| s + d.len() as u64)
+    }
+
+    /// copy buffered data over `out`, which starts at `offset`
+    pub(crate) fn overlay(&self, offset: u64, out: &mut [u8]) {
+        let end = offset + out.len() as u64;
+        for (s, d) in self.ranges.range(..end) {
+            let e = s + d.len() as u64;
+            if e <= offset {
+                continue;
+            }
+            let from = offset.max(*s);
+            let to = end.min(e);
+            out[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&d[(from - s) as usize..(to - s) as usize]);
+        }
+    }
+}
+
+/// per-chunk write-back buffer
+pub struct WriteBuffer {
+    // buffer new small writes, journaled writes recovered on reload are
+    // buffered either way
+    pub(crate) enabled: bool,
+    // flush a chunk once this many bytes are buffered
+    pub(crate) threshold: u64,
+    // flush a chunk whose oldest write is older than this
+    pub(crate) interval: Duration,
+    // apply journaled writes in background as soon as they arrive
+    pub(crate) deferred: bool,
+    // writes up to this size are deferred, whether aligned or not
+    pub(crate) small_write: u64,
+    chunks: Mutex<HashMap<String, ChunkBuffer>>,
+    // signaled when a deferred write arrives
+    ready: Condvar,
+    // a deferred write arrived since the last wait, set under `chunks`
+    arrived: AtomicBool,
+    // serialize flushes of a chunk, striped by chunk name
+    flushing: Vec<tokio::sync::Mutex<()>>,
+}
+
+impl WriteBuffer {
+    pub fn new(enabled: bool, threshold: u64, interval: Duration) -> Self {
+        Self {
+            enabled,
+            threshold,
+            interval,
+            deferred: false,
+            small_write: 0,
+            chunks: Mutex::new(HashMap::new()),
+            ready: Condvar::new(),
+            arrived: AtomicBool::new(false),
+            flushing: (0..FLUSH_STRIPES)
+                .map(|_| tokio::sync::Mutex::new(()))
+                .collect(),
+        }
+    }
+
+    /// apply writes up to `small_write` bytes in background after they
+    /// are journaled
+    pub fn set_deferred(&mut self, small_write: u64) {
+        self.enabled = true;
+        self.deferred = true;
+        self.small_write = small_write;
+    }
+
+    /// whether a write should go to the buffer
+    pub(crate) fn accepts(&self, offset: u64, len: u64, io_size: u64) -> bool {
+        self.enabled
+            && (offset % io_size != 0
+                || len % io_size != 0
+                || (self.deferred && len <= self.small_write))
+    }
+
+    /// wait until a deferred write arrives or `timeout` passes, a write
+    /// arriving while the caller is flushing ends the next wait at once
+    pub(crate) fn wait(&self, timeout: Duration) {
+        let chunks = self.chunks.lock().unwrap();
+        let _ = self
+            .ready
+            .wait_timeout_while(chunks, timeout, |_| {
+                !self.arrived.swap(false, Ordering::SeqCst)
+            })
+            .unwrap();
+    }
+
+    /// buffer a journaled write, return true if the caller should flush
+    /// the chunk, deferred writes are left to the background flusher
+    pub(crate) fn insert(&self, name: &str, seq: u64, offset: u64, data: &[u8]) -> bool {
+        let mut chunks = self.chunks.lock().unwrap();
+        let buffer = chunks
+            .entry(name.to_string())
+            .or_insert_with(ChunkBuffer::new);
+        buffer.insert(offset, data);
+        buffer.seqs.push(seq);
+        if self.deferred {
+            self.arrived.store(true, Ordering::SeqCst);
+            self.ready.notify_one();
+            return false;
+        }
+        buffer.bytes() >= self.threshold
+    }
+
+    /// lock flushes of a chunk, hold it from `snapshot` to `flushed` so an
+    /// older snapshot never reaches the disk after a newer one
+    pub(crate) async fn flush_lock(&self, name: &str) -> tokio::sync::MutexGuard<'_, ()> {
+        let mut h = DefaultHasher::new();
+        name.hash(&mut h);
+        self.flushing[h.finish() as usize % FLUSH_STRIPES]
+            .lock()
+            .await
+    }
+
+    /// copy of the buffered writes of a chunk
+    pub(crate) fn snapshot(&self, name: &str) -> Option<ChunkBuffer> {
+        self.chunks.lock().unwrap().get(name).cloned()
+    }
+
+    /// end of the buffered data of a chunk, 0 if nothing is buffered
+    pub(crate) fn end(&self, name: &str) -> u64 {
+        self.chunks.lock().unwrap().get(name).map_or(0, |b| b.end())
+    }
+
+    /// forget flushed journal records and the data `flushed` has written,
+    /// the chunk is dropped once nothing newer than `flushed` is buffered
+    ///
+    /// what is left arrived after the snapshot, so its age starts again
+    pub(crate) fn flushed(&self, name: &str, flushed: &ChunkBuffer) {
+        let mut chunks = self.chunks.lock().unwrap();
+        if let Some(buffer) = chunks.get_mut(name) {
+            buffer.seqs.retain(|seq| !flushed.seqs.contains(seq));
+            if buffer.seqs.is_empty() {
+                chunks.remove(name);
+                return;
+            }
+            buffer.trim(flushed);
+            buffer.since = Instant::now();
+        }
+    }
+
+    /// drop buffered writes of a chunk
+    pub(crate) fn discard(&self, name: &str) {
+        self.chunks.lock().unwrap().remove(name);
+    }
+
+    /// move buffered writes to another name
+    pub(crate) fn rename(&self, old: &str, new: &str) {
+        let mut chunks = self.chunks.lock().unwrap();
+        chunks.remove(new);
+        if let Some(buffer) = chunks.remove(old) {
+            chunks.insert(new.to_string(), buffer);
+        }
+    }
+
+    /// every buffered chunk
+    pub(crate) fn chunks(&self) -> Vec<String> {
+        self.chunks.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// chunks buffered for longer than the flush interval,
+    /// every buffered chunk in deferred mode
+    pub(crate) fn expired(&self) -> Vec<String> {
+        self.chunks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, b)| self.deferred || b.since.elapsed() >= self.interval)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    pub fn test_insert() {
+        let wb = WriteBuffer::new(true, 16, Duration::from_secs(1));
+        assert!(!wb.insert("a", 0, 4, &[1; 4]));
+        assert!(!wb.insert("a", 1, 10, &[2; 2]));
+        // touches the first range, overlaps the second
+        assert!(!wb.insert("a", 2, 8, &[3; 3]));
+        let buffer = wb.snapshot("a").unwrap();
+        assert_eq!(buffer.ranges.len(), 1);
+        assert_eq!(buffer.ranges[&4], vec![1, 1, 1, 1, 3, 3, 3, 2]);
+        assert_eq!(buffer.seqs, vec![0, 1, 2]);
+        assert_eq!(wb.end("a"), 12);
+        // reaching the threshold asks for a flush
+        assert!(wb.insert("a", 3, 20, &[4; 8]));
+        assert_eq!(wb.snapshot("a").unwrap().bytes(), 16);
+
+        let mut out = [0u8; 8];
+        wb.snapshot("a").unwrap().overlay(2, &mut out);
+        assert_eq!(out, [0, 0, 1, 1, 1, 1, 3, 3]);
+    }
+
+    #[test]
+    pub fn test_snapshot() {
+        let wb = WriteBuffer::new(true, 1 << 20, Duration::from_secs(1));
+        assert!(wb.snapshot("a").is_none());
+        wb.insert("a", 0, 0, &[1; 4]);
+        let buffer = wb.snapshot("a").unwrap();
+        // later writes do not change a snapshot
+        wb.insert("a", 1, 0, &[2; 8]);
+        assert_eq!(buffer.ranges[&0], vec![1; 4]);
+        assert_eq!(buffer.seqs, vec![0]);
+        assert_eq!(wb.snapshot("a").unwrap().ranges[&0], vec![2; 8]);
+    }
+
+    #[test]
+    pub fn test_flushed() {
+        let wb = WriteBuffer::new(true, 1 << 20, Duration::from_secs(1));
+        wb.insert("a", 0, 0, &[1; 8]);
+        let buffer = wb.snapshot("a").unwrap();
+        wb.flushed("a", &buffer);
+        assert!(wb.snapshot("a").is_none());
+
+        wb.insert("a", 1, 0, &[1; 8]);
+        wb.insert("a", 2, 16, &[1; 4]);
+        let buffer = wb.snapshot("a").unwrap();
+        // overwrite part of the first range and add a range after the snapshot
+        wb.insert("a", 3, 2, &[5; 2]);
+        wb.insert("a", 4, 32, &[6; 4]);
+        wb.flushed("a", &buffer);
+        let left = wb.snapshot("a").unwrap();
+        assert_eq!(left.seqs, vec![3, 4]);
+        assert_eq!(left.ranges.len(), 2);
+        assert_eq!(left.ranges[&2], vec![5; 2]);
+        assert_eq!(left.ranges[&32], vec![6; 4]);
+        assert!(left.since >= buffer.since);
+
+        let buffer = wb.snapshot("a").unwrap();
+        wb.flushed("a", &buffer);
+        assert!(wb.snapshot("a").is_none());
+        assert!(wb.chunks().is_empty());
+    }
+
+    #[test]
+    pub fn test_interleaved_flush() {
+        // flush the buffer of chunk "a" to `disk`, `delay` stands for the
+        // time its writes take
+        async fn flush(wb: &WriteBuffer, disk: &Mutex<Vec<u8>>, delay: u64) {
+            let _flush = wb.flush_lock("a").await;
+            let buffer = match wb.snapshot("a") {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            for (offset, data) in buffer.ranges.iter() {
+                let offset = *offset as usize;
+                disk.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+            }
+            wb.flushed("a", &buffer);
+        }
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let wb = WriteBuffer::new(true, 1 << 20, Duration::from_secs(1));
+        let disk = Mutex::new(vec![0u8; 8]);
+        wb.insert("a", 0, 0, &[1; 8]);
+        rt.block_on(async {
+            // the second flush starts while the first one is writing
+            tokio::join!(flush(&wb, &disk, 50), async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                wb.insert("a", 1, 0, &[2; 8]);
+                flush(&wb, &disk, 0).await;
+            });
+        });
+        assert_eq!(*disk.lock().unwrap(), vec![2; 8]);
+        assert!(wb.snapshot("a").is_none());
+    }
+
+    #[test]
+    pub fn test_expired() {
+        let wb = WriteBuffer::new(true, 1 << 20, Duration::from_millis(50));
+        wb.insert("a", 0, 0, &[1; 4]);
+        assert!(wb.expired().is_empty());
+        std::thread::sleep(Duration::from_millis(60));
+        wb.insert("b", 1, 0, &[1; 4]);
+        assert_eq!(wb.expired(), vec!["a".to_string()]);
+
+        // every buffered chunk is due in deferred mode
+        let mut wb = WriteBuffer::new(true, 1 << 20, Duration::from_secs(60));
+        wb.set_deferred(4096);
+        wb.insert("a", 0, 0, &[1; 4]);
+        assert_eq!(wb.expired(), vec!["a".to_string()]);
+    }
+}